@@ -0,0 +1,40 @@
+//! Bridges stdin and stdout to a unix socket, as `systemd-stdio-bridge` does to a bus
+//!
+//! The tests use it to reach a test bus over a `unixexec:` address.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::{fd::AsFd, unix::net::UnixStream},
+};
+
+fn main() -> io::Result<()> {
+    let path = std::env::args_os()
+        .nth(1)
+        .expect("usage: stdio_bridge <socket path>");
+    let socket = UnixStream::connect(path)?;
+
+    // Unbuffered, so that nothing waits for a newline
+    let stdout = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    let from_socket = socket.try_clone()?;
+    let to_stdout = std::thread::spawn(move || forward(from_socket, stdout));
+
+    forward(io::stdin().lock(), &socket)?;
+    socket.shutdown(Shutdown::Write)?;
+    to_stdout.join().expect("the copying thread panicked")
+}
+
+/// Pass on whatever arrives right away, until the end of the input
+///
+/// `io::copy` can splice between the socket and a pipe, which doesn't always pass on a message
+/// before more arrives.
+fn forward(mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(()),
+            len => writer.write_all(&buf[..len])?,
+        }
+    }
+}
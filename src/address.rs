@@ -0,0 +1,425 @@
+use std::{
+    fmt,
+    ops::Range,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use nu_protocol::{record, LabeledError, Span, Spanned, Value};
+
+/// A single D-Bus server address, as found in e.g. `DBUS_SESSION_BUS_ADDRESS`
+///
+/// See the [address format](https://dbus.freedesktop.org/doc/dbus-specification.html#addresses)
+/// in the D-Bus specification. Values are stored unescaped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbusAddress {
+    pub transport: String,
+    pub params: Vec<(String, String)>,
+}
+
+/// A syntax error in an address, with the byte range of the address string it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressError {
+    pub message: String,
+    pub range: Range<usize>,
+}
+
+impl AddressError {
+    fn new(message: impl Into<String>, range: Range<usize>) -> AddressError {
+        AddressError {
+            message: message.into(),
+            range,
+        }
+    }
+
+    /// Convert to a [LabeledError], pointing at the part of the address that is wrong if possible
+    pub fn into_labeled(self, address: &Spanned<impl AsRef<str>>) -> LabeledError {
+        LabeledError::new(format!("Invalid D-Bus address: {}", self.message)).with_label(
+            self.message,
+            sub_span(address.item.as_ref(), address.span, self.range),
+        )
+    }
+}
+
+/// Find the span of part of a string value
+///
+/// This only works if the string was written literally, with or without quotes. Otherwise, the
+/// span of the whole value is used.
+fn sub_span(string: &str, span: Span, range: Range<usize>) -> Span {
+    let offset = match (span.end - span.start).checked_sub(string.len()) {
+        Some(0) => 0,
+        Some(2) => 1,
+        _ => return span,
+    };
+    let start = span.start + offset + range.start;
+    // Make sure there's always something to point at
+    let end = (span.start + offset + range.end).max(start + 1);
+    Span::new(start, end.min(span.end))
+}
+
+impl DbusAddress {
+    /// Parse a `;`-separated list of addresses
+    pub fn parse_list(input: &str) -> Result<Vec<DbusAddress>, AddressError> {
+        let mut addresses = vec![];
+        let mut offset = 0;
+        for entry in input.split(';') {
+            // Empty entries (e.g. a trailing `;`) are allowed and ignored, as libdbus does
+            if !entry.is_empty() {
+                addresses.push(DbusAddress::parse_at(entry, offset)?);
+            }
+            offset += entry.len() + 1;
+        }
+        if addresses.is_empty() {
+            Err(AddressError::new("the address is empty", 0..input.len()))
+        } else {
+            Ok(addresses)
+        }
+    }
+
+    /// Parse a single address that starts at `offset` in the original string
+    fn parse_at(entry: &str, offset: usize) -> Result<DbusAddress, AddressError> {
+        let whole = offset..offset + entry.len();
+        let (transport, rest) = entry.split_once(':').ok_or_else(|| {
+            AddressError::new("missing `:` after the transport name", whole.clone())
+        })?;
+        if transport.is_empty() {
+            return Err(AddressError::new("missing transport name", whole));
+        }
+
+        let mut params: Vec<(String, String)> = vec![];
+        let mut offset = offset + transport.len() + 1;
+        if !rest.is_empty() {
+            for param in rest.split(',') {
+                let range = offset..offset + param.len();
+                let (key, value) = param
+                    .split_once('=')
+                    .ok_or_else(|| AddressError::new("expected `key=value`", range.clone()))?;
+                let key_range = offset..offset + key.len();
+                if key.is_empty() {
+                    return Err(AddressError::new("missing key", range));
+                }
+                if params.iter().any(|(k, _)| k == key) {
+                    return Err(AddressError::new(
+                        format!("`{key}` is specified more than once"),
+                        key_range,
+                    ));
+                }
+                let value = unescape(value, offset + key.len() + 1)?;
+                params.push((key.to_owned(), value));
+                offset += param.len() + 1;
+            }
+        }
+
+        let address = DbusAddress {
+            transport: transport.to_owned(),
+            params,
+        };
+        address
+            .validate()
+            .map_err(|message| AddressError::new(message, whole))?;
+        Ok(address)
+    }
+
+    /// The address of a unix socket at a path
+    pub fn unix_path(path: impl AsRef<Path>) -> DbusAddress {
+        DbusAddress {
+            transport: "unix".into(),
+            params: vec![("path".into(), path.as_ref().to_string_lossy().into_owned())],
+        }
+    }
+
+    /// Get the (unescaped) value of a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Check the keys that are specified for the transports defined by the specification
+    ///
+    /// Other transports are allowed, since implementations can have their own.
+    fn validate(&self) -> Result<(), String> {
+        let transport = self.transport.as_str();
+        let allowed: &[&str] = match transport {
+            "unix" => &["path", "abstract", "dir", "tmpdir", "runtime"],
+            "tcp" => &["host", "bind", "port", "family"],
+            "nonce-tcp" => &["host", "bind", "port", "family", "noncefile"],
+            "unixexec" => &["path"],
+            "launchd" => &["env"],
+            "autolaunch" => &["scope"],
+            "systemd" => &[],
+            _ => return Ok(()),
+        };
+        for (key, _) in &self.params {
+            let is_argv = transport == "unixexec"
+                && key
+                    .strip_prefix("argv")
+                    .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+            if key != "guid" && !allowed.contains(&key.as_str()) && !is_argv {
+                return Err(format!(
+                    "`{key}` is not valid for the {transport} transport (expected one of: {})",
+                    allowed.join(", ")
+                ));
+            }
+        }
+
+        if let Some(guid) = self.get("guid") {
+            if guid.len() != 32 || !guid.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err("guid must be 32 hex digits".into());
+            }
+        }
+
+        match transport {
+            "unix" => {
+                let locations = ["path", "abstract", "dir", "tmpdir", "runtime"]
+                    .into_iter()
+                    .filter(|key| self.get(key).is_some())
+                    .count();
+                if locations != 1 {
+                    return Err("unix addresses need exactly one of path, abstract, dir, \
+                        tmpdir or runtime"
+                        .into());
+                }
+                if self.get("runtime").is_some_and(|value| value != "yes") {
+                    return Err("runtime can only be set to `yes`".into());
+                }
+            }
+            "tcp" | "nonce-tcp" => {
+                if let Some(port) = self.get("port") {
+                    port.parse::<u16>()
+                        .map_err(|_| format!("port {port:?} is not a valid port number"))?;
+                }
+                if let Some(family) = self.get("family") {
+                    if family != "ipv4" && family != "ipv6" {
+                        return Err(format!("family must be ipv4 or ipv6, not {family:?}"));
+                    }
+                }
+                if transport == "nonce-tcp" && self.get("noncefile").is_none() {
+                    return Err("nonce-tcp addresses need a noncefile".into());
+                }
+            }
+            "unixexec" if self.get("path").is_none() => {
+                return Err("unixexec addresses need a path".into())
+            }
+            "launchd" if self.get("env").is_none() => {
+                return Err("launchd addresses need an env".into())
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Check that a client can connect to this address, rather than only listen on it
+    ///
+    /// For `unixexec:` addresses, this also checks that the program can be found, since libdbus
+    /// can't tell us much more than that the connection closed if it can't be run.
+    pub fn check_connectable(&self) -> Result<(), String> {
+        match self.transport.as_str() {
+            "unix" => match ["dir", "tmpdir", "runtime"]
+                .into_iter()
+                .find(|key| self.get(key).is_some())
+            {
+                Some(key) => Err(format!(
+                    "unix addresses with {key} can only be listened on, not connected to"
+                )),
+                None => Ok(()),
+            },
+            "systemd" => Err("systemd addresses can only be listened on".into()),
+            "unixexec" => {
+                let path = self.get("path").unwrap_or_default();
+                if find_program(path).is_some() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "the program {path:?} to run for unixexec was not found"
+                    ))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Represent the address as a nushell record
+    pub fn to_value(&self, span: Span) -> Value {
+        Value::record(
+            record! {
+                "transport" => Value::string(&self.transport, span),
+                "params" => Value::record(
+                    self.params
+                        .iter()
+                        .map(|(key, value)| (key.clone(), Value::string(value, span)))
+                        .collect(),
+                    span,
+                ),
+            },
+            span,
+        )
+    }
+}
+
+impl fmt::Display for DbusAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.transport)?;
+        for (index, (key, value)) in self.params.iter().enumerate() {
+            if index > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{key}={}", escape(value.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Find a program the same way `execvp()` does, which is what libdbus uses for `unixexec:`
+fn find_program(path: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if path.contains('/') {
+        return Some(PathBuf::from(path)).filter(|path| is_executable(path));
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(path))
+        .find(|path| is_executable(path))
+}
+
+/// Escape a value for use in an address
+pub fn escape(value: &[u8]) -> String {
+    let mut out = String::new();
+    for &byte in value {
+        if byte.is_ascii_alphanumeric() || b"-_/.\\*".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02x}", byte));
+        }
+    }
+    out
+}
+
+/// Decode `%xx` escapes in a value that starts at `offset` in the original string
+fn unescape(value: &str, offset: usize) -> Result<String, AddressError> {
+    let bytes = value.as_bytes();
+    let mut out = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let byte = value
+                .get(index + 1..index + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| {
+                    let end = (index + 3).min(bytes.len());
+                    AddressError::new(
+                        "`%` must be followed by two hex digits",
+                        offset + index..offset + end,
+                    )
+                })?;
+            out.push(byte);
+            index += 3;
+        } else {
+            out.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| {
+        AddressError::new(
+            "the escaped value is not valid UTF-8",
+            offset..offset + value.len(),
+        )
+    })
+}
+
+#[cfg(test)]
+fn parse_one(input: &str) -> Result<DbusAddress, AddressError> {
+    DbusAddress::parse_list(input).map(|mut addresses| addresses.remove(0))
+}
+
+#[test]
+fn test_parse_unix_path() {
+    let address = parse_one("unix:path=/run/user/1000/bus").unwrap();
+    assert_eq!(address.transport, "unix");
+    assert_eq!(address.get("path"), Some("/run/user/1000/bus"));
+}
+
+#[test]
+fn test_parse_list() {
+    let addresses = DbusAddress::parse_list(
+        "unix:abstract=/tmp/dbus-x,guid=0123456789abcdef0123456789abcdef;\
+         tcp:host=localhost,port=1234,family=ipv4;",
+    )
+    .unwrap();
+    assert_eq!(addresses.len(), 2);
+    assert_eq!(
+        addresses[0].get("guid"),
+        Some("0123456789abcdef0123456789abcdef")
+    );
+    assert_eq!(addresses[1].transport, "tcp");
+    assert_eq!(addresses[1].get("port"), Some("1234"));
+}
+
+#[test]
+fn test_parse_escapes_roundtrip() {
+    let address = parse_one("unix:path=/tmp/with%20space%2cand%3bmore").unwrap();
+    assert_eq!(address.get("path"), Some("/tmp/with space,and;more"));
+    assert_eq!(
+        address.to_string(),
+        "unix:path=/tmp/with%20space%2cand%3bmore"
+    );
+}
+
+#[test]
+fn test_parse_unixexec_argv() {
+    let address = parse_one("unixexec:path=ssh,argv1=-xT,argv2=host").unwrap();
+    assert_eq!(address.get("argv2"), Some("host"));
+    assert!(parse_one("unixexec:argv1=foo").is_err());
+
+    // Characters that should be escaped are tolerated, as long as they aren't separators
+    let address = parse_one("unixexec:path=ssh,argv1=--bus-path=unix:path=/x").unwrap();
+    assert_eq!(address.get("argv1"), Some("--bus-path=unix:path=/x"));
+    assert_eq!(
+        address.to_string(),
+        "unixexec:path=ssh,argv1=--bus-path%3dunix%3apath%3d/x"
+    );
+}
+
+#[test]
+fn test_parse_errors_have_ranges() {
+    let err = DbusAddress::parse_list("unix:path=/a;tcp:host=x,port=99999").unwrap_err();
+    assert_eq!(err.range, 13..34);
+
+    let err = DbusAddress::parse_list("unix:path=/a%zz").unwrap_err();
+    assert_eq!(err.range, 12..15);
+
+    let err = DbusAddress::parse_list("unix:path=/a,path=/b").unwrap_err();
+    assert_eq!(err.range, 13..17);
+
+    assert!(DbusAddress::parse_list("unix").is_err());
+    assert!(DbusAddress::parse_list(";").is_err());
+    assert!(DbusAddress::parse_list("unix:pth=/a").is_err());
+    assert!(DbusAddress::parse_list("unix:path=/a,guid=xyz").is_err());
+}
+
+#[test]
+fn test_check_connectable() {
+    let listen_only = parse_one("unix:tmpdir=/tmp").unwrap();
+    assert!(listen_only.check_connectable().is_err());
+    let address = parse_one("unix:path=/tmp/bus").unwrap();
+    assert!(address.check_connectable().is_ok());
+    let address = parse_one("unixexec:path=sh,argv1=-c,argv2=true").unwrap();
+    assert!(address.check_connectable().is_ok());
+    let address = parse_one("unixexec:path=/nonexistent/nu_plugin_dbus-bridge").unwrap();
+    assert!(address.check_connectable().is_err());
+}
+
+#[test]
+fn test_sub_span() {
+    let span = Span::new(100, 117);
+    // Quoted: "unix:path=/a%zz"
+    assert_eq!(
+        sub_span("unix:path=/a%zz", span, 12..15),
+        Span::new(113, 116)
+    );
+    // Unknown representation: use the whole span
+    assert_eq!(sub_span("unix:path=/a", span, 5..9), span);
+}
@@ -0,0 +1,304 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use dbus::{arg::ArgType, Message};
+use nu_protocol::{LabeledError, Span, Value};
+use serde_json::{json, Map, Value as Json};
+
+use crate::{config::U64Style, redact::Redaction};
+
+/// An entry in the audit log for a method call that changes something
+///
+/// The log is opened before the call is made, so that nothing is done if it can't be written.
+/// A line is written for each attempt once its outcome is known, so retries are recorded too.
+pub struct AuditEntry {
+    file: File,
+    entry: Map<String, Json>,
+    redaction: Redaction,
+    interface: String,
+    member: String,
+    /// The arguments as they were sent, to find sensitive return values
+    args: Vec<Value>,
+}
+
+impl AuditEntry {
+    /// Open the audit log and describe a method call that is about to be sent on the given bus,
+    /// leaving out sensitive values
+    pub fn start(
+        path: &Path,
+        bus: &str,
+        message: &Message,
+        redaction: &Redaction,
+    ) -> Result<AuditEntry, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("couldn't open {}: {err}", path.display()))?;
+
+        let interface = message
+            .interface()
+            .as_deref()
+            .unwrap_or_default()
+            .to_owned();
+        let member = message.member().as_deref().unwrap_or_default().to_owned();
+        let args = crate::convert::from_message(message, Span::unknown(), U64Style::String, None)?;
+        let mut redacted_args = args.clone();
+        redaction.redact_args(&interface, &member, &mut redacted_args);
+        let json = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "bus": bus,
+            "dest": message.destination().as_deref(),
+            "path": message.path().as_deref(),
+            "interface": interface,
+            "member": member,
+            "signature": signature(message),
+            "args": redacted_args.iter().map(to_json).collect::<Vec<_>>(),
+            "attempt": 1,
+        });
+        let Json::Object(entry) = json else {
+            unreachable!()
+        };
+        Ok(AuditEntry {
+            file,
+            entry,
+            redaction: redaction.clone(),
+            interface,
+            member,
+            args,
+        })
+    }
+
+    /// Start describing another attempt at the same call, sent now
+    pub fn retry(&mut self) {
+        let attempt = self.entry["attempt"].as_u64().unwrap_or_default() + 1;
+        self.entry.insert("attempt".into(), attempt.into());
+        self.entry
+            .insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+    }
+
+    /// Write the entry with the outcome of the attempt: its return value, or the error
+    pub fn record(&mut self, outcome: Result<Value, &LabeledError>) -> Result<(), String> {
+        let (result, error) = match outcome {
+            Ok(mut value) => {
+                self.redaction
+                    .redact_result(&self.interface, &self.member, &self.args, &mut value);
+                (to_json(&value), Json::Null)
+            }
+            Err(err) => (Json::Null, Json::String(err.msg.clone())),
+        };
+        self.entry.insert("result".into(), result);
+        self.entry.insert("error".into(), error);
+
+        // A single write, so that concurrent writers don't interleave lines
+        let mut line = Json::Object(self.entry.clone()).to_string();
+        line.push('\n');
+        self.file
+            .write_all(line.as_bytes())
+            .map_err(|err| format!("couldn't write the audit log: {err}"))
+    }
+}
+
+/// The signature of all of the arguments of a message
+pub fn signature(message: &Message) -> String {
+    let mut iter = message.iter_init();
+    let mut signature = String::new();
+    while iter.arg_type() != ArgType::Invalid {
+        signature.push_str(&iter.signature());
+        iter.next();
+    }
+    signature
+}
+
+/// Convert a value to JSON the way `to json` would, for the types that come from D-Bus
+fn to_json(value: &Value) -> Json {
+    match value {
+        Value::Bool { val, .. } => Json::Bool(*val),
+        Value::Int { val, .. } => Json::from(*val),
+        Value::Float { val, .. } => Json::from(*val),
+        Value::String { val, .. } => Json::String(val.clone()),
+        Value::Binary { val, .. } => Json::from(val.clone()),
+        Value::List { vals, .. } => Json::Array(vals.iter().map(to_json).collect()),
+        Value::Record { val, .. } => Json::Object(
+            val.iter()
+                .map(|(key, value)| (key.clone(), to_json(value)))
+                .collect(),
+        ),
+        Value::Nothing { .. } => Json::Null,
+        other => Json::String(
+            other
+                .coerce_string()
+                .unwrap_or_else(|_| other.get_type().to_string()),
+        ),
+    }
+}
+
+#[test]
+fn test_audit_entry() {
+    let dir = std::env::temp_dir().join(format!("nu_plugin_dbus-audit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.jsonl");
+
+    let message = Message::new_method_call(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+        "StopUnit",
+    )
+    .unwrap()
+    .append2("nginx.service", "replace");
+    let redaction = Redaction::default();
+    let mut entry = AuditEntry::start(
+        &path,
+        "unix:path=/run/dbus/system_bus_socket",
+        &message,
+        &redaction,
+    )
+    .unwrap();
+    entry
+        .record(Err(&LabeledError::new("Access denied")))
+        .unwrap();
+    entry.retry();
+    entry
+        .record(Ok(Value::test_list(vec![Value::test_string("/job/1")])))
+        .unwrap();
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let entries = log
+        .lines()
+        .map(|line| serde_json::from_str::<Json>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["dest"], "org.freedesktop.systemd1");
+    assert_eq!(entries[0]["member"], "StopUnit");
+    assert_eq!(entries[0]["signature"], "ss");
+    assert_eq!(entries[0]["args"], json!(["nginx.service", "replace"]));
+    assert_eq!(entries[0]["attempt"], 1);
+    assert_eq!(entries[0]["result"], Json::Null);
+    assert_eq!(entries[0]["error"], "Access denied");
+    assert!(entries[0]["timestamp"].is_string());
+    assert_eq!(entries[1]["member"], "StopUnit");
+    assert_eq!(entries[1]["attempt"], 2);
+    assert_eq!(entries[1]["result"], json!(["/job/1"]));
+    assert_eq!(entries[1]["error"], Json::Null);
+}
+
+#[test]
+fn test_audit_log_of_calls() {
+    use nu_protocol::{Signals, Spanned};
+
+    use crate::{
+        bench::BenchOptions,
+        client::{bus_call, spanned, BatchCall, TestClient},
+    };
+
+    let dir = std::env::temp_dir().join(format!(
+        "nu_plugin_dbus-audit-client-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.jsonl");
+
+    let test = TestClient::start(|config| {
+        config.introspect = false;
+        config.audit_log = Some(Spanned {
+            item: path.clone(),
+            span: Span::test_data(),
+        });
+        // Allowing a method doesn't make it a read
+        config.safety.allow = vec!["org.freedesktop.DBus.RequestName".into()];
+        // Each attempt is recorded
+        config.retry.attempts = 2;
+        config.retry.errors = vec!["org.freedesktop.DBus.Error.UnknownMethod".into()];
+        config.redaction.rules.push(crate::redact::RedactionRule {
+            interface: "org.freedesktop.DBus".into(),
+            member: "RequestName".into(),
+            arg: Some(0),
+            key: None,
+        });
+    });
+    let client = test.client();
+
+    // Reads aren't recorded
+    bus_call(&client, "org.freedesktop.DBus.Peer", "Ping", "", &[]).unwrap();
+    bus_call(
+        &client,
+        "org.freedesktop.DBus",
+        "RequestName",
+        "su",
+        &[
+            Value::test_string("com.example.Audited"),
+            Value::test_int(0),
+        ],
+    )
+    .unwrap();
+    assert!(bus_call(&client, "org.freedesktop.DBus", "Shutdown", "", &[]).is_err());
+
+    let request_name = |name: &str| {
+        Ok(BatchCall {
+            dest: spanned("org.freedesktop.DBus"),
+            object: spanned("/org/freedesktop/DBus"),
+            interface: spanned("org.freedesktop.DBus"),
+            method: spanned("RequestName"),
+            signature: Some(spanned("su")),
+            args: vec![Value::test_string(name), Value::test_int(0)],
+        })
+    };
+    // Benchmarks of anything but reads would make too many calls to record
+    let options = BenchOptions {
+        count: Some(5),
+        duration: None,
+        concurrency: 1,
+    };
+    let bench = |call| client.bench(&call, &options, &Signals::empty());
+    let err = bench(request_name("com.example.Benched").unwrap()).unwrap_err();
+    assert!(err.msg.starts_with("Can't benchmark"), "{}", err.msg);
+    let ping = BatchCall {
+        interface: spanned("org.freedesktop.DBus.Peer"),
+        method: spanned("Ping"),
+        signature: None,
+        args: vec![],
+        ..request_name("").unwrap()
+    };
+    bench(ping).unwrap();
+
+    // Calls in a batch are recorded even if the rows are dropped before they're reached
+    let mut rows = test
+        .client()
+        .batch(
+            vec![
+                request_name("com.example.Batch1"),
+                request_name("com.example.Batch2"),
+            ],
+            Span::test_data(),
+        )
+        .unwrap();
+    assert!(rows.next().is_some());
+    drop(rows);
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let entries = log
+        .lines()
+        .map(|line| serde_json::from_str::<Json>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(entries[0]["bus"], test.bus.address());
+    assert_eq!(entries[0]["member"], "RequestName");
+    assert_eq!(entries[0]["signature"], "su");
+    assert_eq!(entries[0]["args"], json!(["<redacted>", 0]));
+    // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    assert_eq!(entries[0]["result"], json!([1]));
+    assert_eq!(entries[0]["attempt"], 1);
+    for (entry, attempt) in entries[1..3].iter().zip(1..) {
+        assert_eq!(entry["member"], "Shutdown");
+        assert_eq!(entry["attempt"], attempt);
+        assert!(entry["error"].is_string());
+    }
+    assert_eq!(entries[3]["member"], "RequestName");
+    assert_eq!(entries[4]["member"], "RequestName");
+}
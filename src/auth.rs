@@ -0,0 +1,432 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+/// The mechanisms offered by our own servers (the test bus and the local end of the auth relay)
+const SERVER_MECHANISMS: &str = "EXTERNAL ANONYMOUS";
+
+/// SASL mechanisms that can be used to authenticate to a D-Bus server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMechanism {
+    /// Credentials passed over the unix socket
+    External,
+    /// A secret cookie shared through `~/.dbus-keyrings`
+    CookieSha1,
+    /// No authentication, if the server allows it
+    Anonymous,
+}
+
+impl AuthMechanism {
+    pub const ALL: [AuthMechanism; 3] = [
+        AuthMechanism::External,
+        AuthMechanism::CookieSha1,
+        AuthMechanism::Anonymous,
+    ];
+
+    /// The name of the mechanism in the protocol
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthMechanism::External => "EXTERNAL",
+            AuthMechanism::CookieSha1 => "DBUS_COOKIE_SHA1",
+            AuthMechanism::Anonymous => "ANONYMOUS",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<AuthMechanism> {
+        AuthMechanism::ALL
+            .into_iter()
+            .find(|mechanism| mechanism.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// The real user id of this process
+pub fn current_uid() -> u32 {
+    // SAFETY: getuid() has no preconditions and can't fail
+    unsafe { libc::getuid() }
+}
+
+/// Run the client side of the SASL handshake, trying each mechanism in order until one is
+/// accepted. Returns the accepted mechanism and the server's GUID.
+///
+/// Cookies for `DBUS_COOKIE_SHA1` are looked for in `keyring_dir`, or `~/.dbus-keyrings` if not
+/// specified.
+pub fn client_handshake(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    mechanisms: &[AuthMechanism],
+    keyring_dir: Option<&Path>,
+) -> io::Result<(AuthMechanism, String)> {
+    let keyring_dir = match keyring_dir {
+        Some(dir) => dir.to_owned(),
+        None => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".dbus-keyrings"))
+            .unwrap_or_default(),
+    };
+    let uid = current_uid().to_string();
+
+    writer.write_all(b"\0")?;
+
+    let mut offered: Option<Vec<String>> = None;
+    let mut tried = vec![];
+    let mut problems = vec![];
+    for &mechanism in mechanisms {
+        // Don't bother with mechanisms the server has already told us it doesn't support
+        if offered
+            .as_ref()
+            .is_some_and(|offered| !offered.iter().any(|name| name == mechanism.name()))
+        {
+            continue;
+        }
+        tried.push(mechanism.name());
+
+        let initial_response = match mechanism {
+            AuthMechanism::External | AuthMechanism::CookieSha1 => hex(uid.as_bytes()),
+            AuthMechanism::Anonymous => hex(b"nu_plugin_dbus"),
+        };
+        write_line(
+            writer,
+            &format!("AUTH {} {initial_response}", mechanism.name()),
+        )?;
+
+        loop {
+            let line = read_line(reader)?;
+            let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+            match command {
+                "OK" => {
+                    write_line(writer, "BEGIN")?;
+                    return Ok((mechanism, arg.to_owned()));
+                }
+                "REJECTED" => {
+                    offered = Some(arg.split_whitespace().map(|s| s.to_owned()).collect());
+                    break;
+                }
+                "DATA" => match mechanism {
+                    AuthMechanism::CookieSha1 => match cookie_sha1_response(arg, &keyring_dir) {
+                        Ok(response) => write_line(writer, &format!("DATA {response}"))?,
+                        Err(problem) => {
+                            problems.push(format!("{}: {problem}", mechanism.name()));
+                            write_line(writer, "CANCEL")?;
+                        }
+                    },
+                    _ => write_line(writer, "DATA")?,
+                },
+                "ERROR" => write_line(writer, "CANCEL")?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected response from server during authentication: {line:?}"),
+                    ))
+                }
+            }
+        }
+    }
+
+    let mut message = format!("authentication failed (tried: {}", tried.join(", "));
+    if let Some(offered) = offered {
+        message.push_str(&format!("; the server offered: {}", offered.join(", ")));
+    }
+    message.push(')');
+    for problem in problems {
+        message.push_str(&format!("; {problem}"));
+    }
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, message))
+}
+
+/// Compute the response to a `DBUS_COOKIE_SHA1` challenge
+fn cookie_sha1_response(challenge: &str, keyring_dir: &Path) -> Result<String, String> {
+    let challenge = unhex(challenge)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or("the server sent an invalid challenge")?;
+    let [context, cookie_id, server_challenge] = challenge
+        .split(' ')
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| "the server sent an invalid challenge")?;
+    if context.is_empty() || context.starts_with('.') || context.contains(['/', '\\']) {
+        return Err(format!(
+            "the server sent an invalid cookie context {context:?}"
+        ));
+    }
+
+    let keyring_path = keyring_dir.join(context);
+    let keyring = std::fs::read_to_string(&keyring_path)
+        .map_err(|err| format!("can't read {}: {err}", keyring_path.display()))?;
+    // Each line is: <id> <creation time> <cookie>
+    let cookie = keyring
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .find(|fields| fields.len() == 3 && fields[0] == cookie_id)
+        .map(|fields| fields[2])
+        .ok_or_else(|| format!("cookie {cookie_id} not found in {}", keyring_path.display()))?;
+
+    let client_challenge = random_challenge()
+        .map_err(|err| format!("can't read a random challenge from /dev/urandom: {err}"))?;
+    let digest = sha1(format!("{server_challenge}:{client_challenge}:{cookie}").as_bytes());
+    Ok(hex(
+        format!("{client_challenge} {}", hex(&digest)).as_bytes()
+    ))
+}
+
+/// Run the server side of the SASL handshake, accepting `EXTERNAL` and `ANONYMOUS`, and passing
+/// file descriptors if `unix_fds` is set. Returns any bytes read past the `BEGIN` line.
+///
+/// Credentials aren't checked, so this must only be used on sockets that only our own user can
+/// reach.
+pub fn server_handshake(
+    stream: &mut UnixStream,
+    guid: &str,
+    unix_fds: bool,
+) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    let mut chunk = [0; 256];
+    let mut nul_received = false;
+    let mut authenticated = false;
+    let mut waiting_for_data = false;
+
+    loop {
+        // Process complete lines in the buffer
+        if !nul_received && !buf.is_empty() {
+            if buf[0] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected nul byte",
+                ));
+            }
+            buf.remove(0);
+            nul_received = true;
+        }
+        while let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = String::from_utf8_lossy(&buf[..pos]).into_owned();
+            buf.drain(..pos + 2);
+
+            let mut words = line.split(' ');
+            let command = words.next().unwrap_or_default();
+            let reply = match command {
+                "AUTH" => match words.next() {
+                    Some("EXTERNAL") if words.next().is_none() => {
+                        waiting_for_data = true;
+                        "DATA".into()
+                    }
+                    Some("EXTERNAL") | Some("ANONYMOUS") => {
+                        authenticated = true;
+                        format!("OK {guid}")
+                    }
+                    _ => format!("REJECTED {SERVER_MECHANISMS}"),
+                },
+                "DATA" if waiting_for_data => {
+                    waiting_for_data = false;
+                    authenticated = true;
+                    format!("OK {guid}")
+                }
+                "CANCEL" => {
+                    waiting_for_data = false;
+                    format!("REJECTED {SERVER_MECHANISMS}")
+                }
+                "BEGIN" if authenticated => return Ok(buf),
+                "NEGOTIATE_UNIX_FD" if authenticated && unix_fds => "AGREE_UNIX_FD".into(),
+                "NEGOTIATE_UNIX_FD" if authenticated => "ERROR not supported".into(),
+                _ => "ERROR".into(),
+            };
+            write_line(stream, &reply)?;
+        }
+
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+fn write_line(writer: &mut impl Write, line: &str) -> io::Result<()> {
+    writer.write_all(format!("{line}\r\n").as_bytes())
+}
+
+/// Read a line, one byte at a time so that nothing past the end of the handshake is consumed
+fn read_line(reader: &mut impl Read) -> io::Result<String> {
+    let mut line = vec![];
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if reader.read(&mut byte)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection during authentication",
+            ));
+        }
+        line.push(byte[0]);
+        if line.len() > 16384 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "line too long during authentication",
+            ));
+        }
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// A 128-bit hex challenge for `DBUS_COOKIE_SHA1`, from the OS random number generator, so that
+/// it can't be predicted
+fn random_challenge() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex(&bytes))
+}
+
+/// Generate a 128-bit hex GUID for our own servers. It only needs to be unique, not
+/// cryptographically secure
+pub fn random_guid() -> String {
+    let mut guid = String::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        guid.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    guid
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// SHA-1, which `DBUS_COOKIE_SHA1` requires. It isn't used for anything else
+fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1_smol::Sha1::from(data).digest().bytes()
+}
+
+/// A server that only accepts `DBUS_COOKIE_SHA1`, with a cookie from `keyring_dir`
+#[cfg(test)]
+fn serve_cookie_sha1(stream: &mut UnixStream, keyring_dir: &Path) -> io::Result<()> {
+    std::fs::write(keyring_dir.join("org_freedesktop_general"), "7 0 s3cr3t\n")?;
+
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    let mut reader = stream.try_clone()?;
+    let mut server_challenge = None;
+    loop {
+        let line = read_line(&mut reader)?;
+        let reply = match line.split(' ').collect::<Vec<_>>()[..] {
+            ["AUTH", "DBUS_COOKIE_SHA1", _] => {
+                let challenge = random_challenge()?;
+                let data = format!("org_freedesktop_general 7 {challenge}");
+                server_challenge = Some(challenge);
+                format!("DATA {}", hex(data.as_bytes()))
+            }
+            ["DATA", response] => {
+                let response = String::from_utf8(unhex(response).unwrap()).unwrap();
+                let (client_challenge, digest) = response.split_once(' ').unwrap();
+                let expected = sha1(
+                    format!(
+                        "{}:{client_challenge}:s3cr3t",
+                        server_challenge.as_ref().unwrap()
+                    )
+                    .as_bytes(),
+                );
+                if digest == hex(&expected) {
+                    "OK 0123456789abcdef0123456789abcdef".into()
+                } else {
+                    "REJECTED DBUS_COOKIE_SHA1".into()
+                }
+            }
+            ["BEGIN"] => return Ok(()),
+            _ => "REJECTED DBUS_COOKIE_SHA1".into(),
+        };
+        write_line(stream, &reply)?;
+    }
+}
+
+#[test]
+fn test_sha1() {
+    assert_eq!(
+        hex(&sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+        hex(&sha1(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+}
+
+#[test]
+fn test_client_handshake_falls_back_to_offered_mechanism() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let server_thread = std::thread::spawn(move || server_handshake(&mut server, "0123", false));
+
+    let (mechanism, guid) = client_handshake(
+        &mut &client,
+        &mut &client,
+        &[AuthMechanism::CookieSha1, AuthMechanism::Anonymous],
+        Some(Path::new("/nonexistent")),
+    )
+    .unwrap();
+    assert_eq!(mechanism, AuthMechanism::Anonymous);
+    assert_eq!(guid, "0123");
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn test_client_handshake_reports_offered_mechanisms() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || server_handshake(&mut server, "0123", false));
+
+    let err = client_handshake(
+        &mut &client,
+        &mut &client,
+        &[AuthMechanism::CookieSha1],
+        Some(Path::new("/nonexistent")),
+    )
+    .unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("tried: DBUS_COOKIE_SHA1"), "{message}");
+    assert!(
+        message.contains("the server offered: EXTERNAL, ANONYMOUS"),
+        "{message}"
+    );
+}
+
+#[test]
+fn test_client_handshake_cookie_sha1() {
+    let keyring_dir =
+        std::env::temp_dir().join(format!("nu_plugin_dbus-keyring-{}", std::process::id()));
+    std::fs::create_dir_all(&keyring_dir).unwrap();
+
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let server_thread = {
+        let keyring_dir = keyring_dir.clone();
+        std::thread::spawn(move || serve_cookie_sha1(&mut server, &keyring_dir))
+    };
+    let result = client_handshake(
+        &mut &client,
+        &mut &client,
+        &[AuthMechanism::External, AuthMechanism::CookieSha1],
+        Some(&keyring_dir),
+    );
+    let server_result = server_thread.join().unwrap();
+    let _ = std::fs::remove_dir_all(&keyring_dir);
+
+    assert_eq!(
+        result.unwrap(),
+        (
+            AuthMechanism::CookieSha1,
+            "0123456789abcdef0123456789abcdef".into()
+        )
+    );
+    server_result.unwrap();
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, time::Duration};
+
+use nu_protocol::{record, Span, Value};
+
+/// How much load `dbus bench` puts on the bus
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// How many calls to make in total, if limited
+    pub count: Option<usize>,
+    /// How long to keep making calls for, if limited
+    pub duration: Option<Duration>,
+    /// How many calls to have waiting for a reply at once
+    pub concurrency: usize,
+}
+
+impl BenchOptions {
+    /// The number of calls made if neither a count nor a duration is given
+    pub const DEFAULT_COUNT: usize = 1000;
+}
+
+/// The outcome of every call made by `dbus bench`
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    /// The latency of each successful call
+    latencies: Vec<Duration>,
+    /// The number of failed calls by error name
+    errors: HashMap<String, usize>,
+    /// How long it took from the first call being sent to the last reply
+    elapsed: Duration,
+}
+
+impl BenchReport {
+    pub fn add_success(&mut self, latency: Duration) {
+        self.latencies.push(latency);
+    }
+
+    pub fn add_error(&mut self, name: &str) {
+        *self.errors.entry(name.to_owned()).or_default() += 1;
+    }
+
+    pub fn finish(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+        self.latencies.sort_unstable();
+    }
+
+    /// The latency that `percent` percent of successful calls were at or below (nearest rank)
+    fn percentile(&self, percent: usize) -> Option<Duration> {
+        let rank = (self.latencies.len() * percent).div_ceil(100).max(1);
+        self.latencies.get(rank - 1).copied()
+    }
+
+    fn mean(&self) -> Option<Duration> {
+        let total: Duration = self.latencies.iter().sum();
+        u32::try_from(self.latencies.len())
+            .ok()
+            .filter(|len| *len > 0)
+            .map(|len| total / len)
+    }
+
+    pub fn to_value(&self, span: Span) -> Value {
+        let duration = |latency: Option<Duration>| {
+            latency.map_or(Value::nothing(span), |latency| {
+                Value::duration(latency.as_nanos().try_into().unwrap_or(i64::MAX), span)
+            })
+        };
+        let failed: usize = self.errors.values().sum();
+        let calls = self.latencies.len() + failed;
+
+        let mut errors = self.errors.iter().collect::<Vec<_>>();
+        errors.sort_by(|(a_name, a_count), (b_name, b_count)| {
+            b_count.cmp(a_count).then(a_name.cmp(b_name))
+        });
+        let errors = errors
+            .into_iter()
+            .map(|(name, count)| {
+                Value::record(
+                    record!(
+                        "error" => Value::string(name, span),
+                        "count" => Value::int(*count as i64, span),
+                    ),
+                    span,
+                )
+            })
+            .collect();
+
+        Value::record(
+            record!(
+                "calls" => Value::int(calls as i64, span),
+                "succeeded" => Value::int(self.latencies.len() as i64, span),
+                "failed" => Value::int(failed as i64, span),
+                "elapsed" => duration(Some(self.elapsed)),
+                "calls_per_second" => Value::float(
+                    calls as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+                    span,
+                ),
+                "latency" => Value::record(
+                    record!(
+                        "min" => duration(self.latencies.first().copied()),
+                        "mean" => duration(self.mean()),
+                        "p50" => duration(self.percentile(50)),
+                        "p95" => duration(self.percentile(95)),
+                        "p99" => duration(self.percentile(99)),
+                        "max" => duration(self.latencies.last().copied()),
+                    ),
+                    span,
+                ),
+                "errors" => Value::list(errors, span),
+            ),
+            span,
+        )
+    }
+}
+
+#[test]
+fn test_bench_report_statistics() {
+    let mut report = BenchReport::default();
+    for millis in (1..=100).rev() {
+        report.add_success(Duration::from_millis(millis));
+    }
+    report.add_error("org.freedesktop.DBus.Error.NoReply");
+    report.add_error("org.freedesktop.DBus.Error.AccessDenied");
+    report.add_error("org.freedesktop.DBus.Error.NoReply");
+    report.finish(Duration::from_secs(2));
+
+    assert_eq!(report.percentile(50), Some(Duration::from_millis(50)));
+    assert_eq!(report.percentile(95), Some(Duration::from_millis(95)));
+    assert_eq!(report.percentile(99), Some(Duration::from_millis(99)));
+    assert_eq!(report.mean(), Some(Duration::from_micros(50_500)));
+
+    let value = report.to_value(Span::test_data());
+    let ms = |millis: i64| Value::test_duration(millis * 1_000_000);
+    assert_eq!(value.get_data_by_key("calls"), Some(Value::test_int(103)));
+    assert_eq!(value.get_data_by_key("failed"), Some(Value::test_int(3)));
+    assert_eq!(
+        value.get_data_by_key("calls_per_second"),
+        Some(Value::test_float(51.5))
+    );
+    let latency = value.get_data_by_key("latency").unwrap();
+    assert_eq!(latency.get_data_by_key("min"), Some(ms(1)));
+    assert_eq!(latency.get_data_by_key("max"), Some(ms(100)));
+    let errors = value.get_data_by_key("errors").unwrap();
+    assert_eq!(
+        errors.into_list().unwrap()[0].get_data_by_key("error"),
+        Some(Value::test_string("org.freedesktop.DBus.Error.NoReply"))
+    );
+}
+
+#[test]
+fn test_bench_report_empty() {
+    let mut report = BenchReport::default();
+    report.finish(Duration::ZERO);
+    assert_eq!(report.percentile(50), None);
+    assert_eq!(report.mean(), None);
+}
+
+#[test]
+fn test_bench() {
+    use nu_protocol::Signals;
+
+    use crate::client::{ping_for, spanned, BatchCall, TestClient};
+
+    let test = TestClient::start(|config| config.introspect = false);
+    let client = test.client();
+    let span = Span::test_data();
+    let call = |method: &str| BatchCall {
+        dest: spanned("org.freedesktop.DBus"),
+        object: spanned("/org/freedesktop/DBus"),
+        interface: spanned("org.freedesktop.DBus.Peer"),
+        method: spanned(method),
+        signature: None,
+        args: vec![],
+    };
+    let options = BenchOptions {
+        count: Some(20),
+        duration: None,
+        concurrency: 4,
+    };
+
+    let report = client
+        .bench(&call("Ping"), &options, &Signals::empty())
+        .unwrap()
+        .to_value(span);
+    assert_eq!(
+        report.get_data_by_key("succeeded"),
+        Some(Value::test_int(20))
+    );
+    assert_eq!(report.get_data_by_key("failed"), Some(Value::test_int(0)));
+
+    let report = client
+        .bench(&call("Pong"), &options, &Signals::empty())
+        .unwrap()
+        .to_value(span);
+    assert_eq!(report.get_data_by_key("failed"), Some(Value::test_int(20)));
+    let errors = report
+        .get_data_by_key("errors")
+        .unwrap()
+        .into_list()
+        .unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].get_data_by_key("error"),
+        Some(Value::test_string(
+            "org.freedesktop.DBus.Error.UnknownMethod"
+        ))
+    );
+
+    // Calls made on the same connection during a benchmark still get their replies
+    let options = BenchOptions {
+        count: None,
+        duration: Some(Duration::from_millis(200)),
+        concurrency: 4,
+    };
+    let other = test.client();
+    std::thread::scope(|scope| {
+        let ping = scope.spawn(|| ping_for(&other, Duration::from_millis(150)));
+        client
+            .bench(&call("Ping"), &options, &Signals::empty())
+            .unwrap();
+        ping.join().unwrap().unwrap();
+    });
+}
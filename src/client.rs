@@ -1,14 +1,21 @@
-use dbus::{
-    arg::messageitem::MessageItem,
-    channel::{BusType, Channel},
-    Message,
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use nu_protocol::{LabeledError, Spanned, Value};
+
+use dbus::{arg::messageitem::MessageItem, Message};
+use nu_protocol::{record, LabeledError, Record, Signals, Span, Spanned, Value};
 
 use crate::{
-    config::{DbusBusChoice, DbusClientConfig},
-    convert::to_message_item,
+    audit::AuditEntry,
+    bench::{BenchOptions, BenchReport},
+    config::{DbusClientConfig, SafetyPolicy},
+    connection::{BackgroundCall, DbusConnection},
+    convert::{to_message_item, FdSource},
     dbus_type::DbusType,
+    fd::FdStore,
     introspection::Node,
     pattern::Pattern,
 };
@@ -16,7 +23,22 @@ use crate::{
 /// Executes D-Bus actions on a connection, handling nushell types
 pub struct DbusClient {
     config: DbusClientConfig,
-    conn: Channel,
+    conn: Arc<DbusConnection>,
+    /// Introspection results by destination and object, so that repeated calls don't have to
+    /// introspect again
+    introspection: Mutex<HashMap<(String, String), Result<Node, LabeledError>>>,
+    /// Where file descriptors received in replies are kept open
+    fds: FdStore,
+}
+
+/// A method call to make as part of a batch, or repeatedly by `dbus bench`
+pub struct BatchCall {
+    pub dest: Spanned<String>,
+    pub object: Spanned<String>,
+    pub interface: Spanned<String>,
+    pub method: Spanned<String>,
+    pub signature: Option<Spanned<String>>,
+    pub args: Vec<Value>,
 }
 
 // Convenience macros for error handling
@@ -28,39 +50,597 @@ macro_rules! validate_with {
 }
 
 impl DbusClient {
-    pub fn new(config: DbusClientConfig) -> Result<DbusClient, LabeledError> {
-        // Try to connect to the correct D-Bus destination, as specified in the config
-        let channel = match &config.bus_choice.item {
-            DbusBusChoice::Session => Channel::get_private(BusType::Session),
-            DbusBusChoice::System => Channel::get_private(BusType::System),
-            DbusBusChoice::Started => Channel::get_private(BusType::Starter),
-            DbusBusChoice::Peer(address) => Channel::open_private(address),
-            DbusBusChoice::Bus(address) => Channel::open_private(address).and_then(|mut ch| {
-                ch.register()?;
-                Ok(ch)
+    pub fn new(config: DbusClientConfig, conn: Arc<DbusConnection>) -> DbusClient {
+        DbusClient {
+            config,
+            conn,
+            introspection: Mutex::default(),
+            fds: FdStore::default(),
+        }
+    }
+
+    /// Keep file descriptors received in replies in this store, rather than one of the client's own
+    pub fn with_fds(self, fds: FdStore) -> DbusClient {
+        DbusClient { fds, ..self }
+    }
+
+    /// Where the files to send as file descriptors come from
+    fn fd_source(&self) -> FdSource<'_> {
+        FdSource {
+            cwd: &self.config.cwd,
+            fds: &self.fds,
+        }
+    }
+
+    /// Note that this client's connection is used by a call in the background, until the guard
+    /// is dropped
+    pub fn background_call(&self) -> BackgroundCall {
+        self.conn.background_call()
+    }
+
+    fn error(&self, err: impl std::fmt::Display, msg: impl std::fmt::Display) -> LabeledError {
+        LabeledError::new(err.to_string()).with_label(msg.to_string(), self.config.span)
+    }
+
+    /// The error for a message that couldn't be queued to send, which is usually because the
+    /// connection was closed, unless it carries file descriptors that the connection can't pass
+    fn send_failed(&self, carries_fds: bool, context: &str) -> LabeledError {
+        if carries_fds {
+            self.error("Cannot send file descriptors on this connection", context)
+                .with_help(FD_PASSING_HELP)
+        } else {
+            self.error("the connection was closed", context)
+        }
+    }
+
+    /// Refuse to call a method if the safety policy doesn't allow it
+    fn check_safety(&self, interface: &str, method: &Spanned<String>) -> Result<(), LabeledError> {
+        match self.config.safety.refusal(interface, &method.item) {
+            Some(reason) => Err(LabeledError::new("Refused by the D-Bus safety policy")
+                .with_label(reason, method.span)
+                .with_help(
+                    "in read-only mode, use --unsafe if you're sure, or allow it in \
+                     $env.config.plugins.dbus.safety.allow. Denied methods can only be allowed by \
+                     changing $env.config.plugins.dbus.safety.deny",
+                )),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolve aliases and glob patterns given as a destination to a single name on the bus
+    pub fn resolve_dest(&self, dest: &Spanned<String>) -> Result<Spanned<String>, LabeledError> {
+        let name = self.config.aliases.get(&dest.item).unwrap_or(&dest.item);
+
+        if !name.contains(['*', '?']) {
+            return Ok(Spanned {
+                item: name.clone(),
+                span: dest.span,
+            });
+        }
+
+        let pattern = Pattern::new(name, Some('.'));
+        let mut matches = self.list(Some(&pattern))?;
+        match matches.len() {
+            1 => Ok(Spanned {
+                item: matches.remove(0),
+                span: dest.span,
             }),
+            0 => {
+                // Show what could have been meant, leaving out the unique names
+                let available = self
+                    .list(None)?
+                    .into_iter()
+                    .filter(|name| !name.starts_with(':'))
+                    .collect::<Vec<_>>();
+                Err(
+                    LabeledError::new(format!("No names on the bus match {name:?}"))
+                        .with_label("destination pattern specified here", dest.span)
+                        .with_help(format!("available names: {}", available.join(", "))),
+                )
+            }
+            _ => Err(
+                LabeledError::new(format!("More than one name on the bus matches {name:?}"))
+                    .with_label("destination pattern specified here", dest.span)
+                    .with_help(format!(
+                        "use a more specific pattern to pick one of: {}",
+                        matches.join(", ")
+                    )),
+            ),
+        }
+    }
+
+    /// Run an operation concurrently for every name on the bus that matches a pattern (or alias)
+    ///
+    /// Returns a table with the outcome for each name. An error for one name doesn't prevent the
+    /// operation from being run on the others.
+    pub fn for_each_matching(
+        &self,
+        pattern: &Spanned<String>,
+        span: Span,
+        op: impl Fn(&Spanned<String>) -> Result<Value, LabeledError> + Sync,
+    ) -> Result<Value, LabeledError> {
+        let name = self
+            .config
+            .aliases
+            .get(&pattern.item)
+            .unwrap_or(&pattern.item);
+        let dests = self.list(Some(&Pattern::new(name, Some('.'))))?;
+
+        let results = std::thread::scope(|scope| {
+            let op = &op;
+            let threads = dests
+                .iter()
+                .map(|dest| {
+                    let dest = Spanned {
+                        item: dest.clone(),
+                        span: pattern.span,
+                    };
+                    scope.spawn(move || op(&dest))
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| {
+                    thread
+                        .join()
+                        .unwrap_or_else(|_| Err(LabeledError::new("operation panicked")))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let rows = dests
+            .into_iter()
+            .zip(results)
+            .map(|(dest, result)| {
+                let (status, result, error) = match result {
+                    Ok(value) => ("ok", value, Value::nothing(span)),
+                    Err(err) => ("error", Value::nothing(span), Value::string(err.msg, span)),
+                };
+                Value::record(
+                    record! {
+                        "dest" => Value::string(dest, span),
+                        "status" => Value::string(status, span),
+                        "result" => result,
+                        "error" => error,
+                    },
+                    span,
+                )
+            })
+            .collect();
+        Ok(Value::list(rows, span))
+    }
+
+    /// Apply the configured header flags to a method call, and wait for the destination to
+    /// appear if requested
+    ///
+    /// The bus only starts the destination if auto-start is enabled, and the destination can only
+    /// ask the user for authorization if interactive auth is enabled.
+    fn prepare(&self, mut message: Message) -> Result<Message, LabeledError> {
+        if !self.config.auto_start {
+            message.set_auto_start(false);
+        }
+        if self.config.interactive_auth {
+            allow_interactive_authorization(&mut message);
+        }
+        if let (Some(wait), Some(dest)) = (&self.config.wait_for_name, message.destination()) {
+            self.wait_for_name(&dest, wait)?;
+        }
+        Ok(message)
+    }
+
+    /// Start an audit log entry for a method call, if there is an audit log and the method does
+    /// more than read something
+    ///
+    /// Only method calls are sent for now. Anything else that's sent, such as a signal from a
+    /// future command to emit one, has to be recorded through here too.
+    fn audit(&self, message: &Message) -> Result<Option<AuditEntry>, LabeledError> {
+        let Some(path) = &self.config.audit_log else {
+            return Ok(None);
+        };
+        let (Some(interface), Some(member)) = (message.interface(), message.member()) else {
+            return Ok(None);
+        };
+        if SafetyPolicy::is_read(&interface, &member) {
+            return Ok(None);
         }
+        AuditEntry::start(
+            &path.item,
+            self.conn.address(),
+            message,
+            &self.config.redaction,
+        )
+        .map(Some)
         .map_err(|err| {
-            LabeledError::new(err.to_string()).with_label(
-                "while connecting to D-Bus as specified here",
-                config.bus_choice.span,
+            LabeledError::new("Couldn't open the D-Bus audit log, so nothing was sent")
+                .with_label(err, path.span)
+        })
+    }
+
+    /// Record the outcome of an attempt at a call in its audit log entry
+    fn record_audit(
+        &self,
+        entry: &mut AuditEntry,
+        outcome: Result<Value, &LabeledError>,
+    ) -> Result<(), LabeledError> {
+        entry.record(outcome).map_err(|err| {
+            let span = self
+                .config
+                .audit_log
+                .as_ref()
+                .map_or(self.config.span, |path| path.span);
+            LabeledError::new("The call was made, but couldn't be recorded in the D-Bus audit log")
+                .with_label(err, span)
+        })
+    }
+
+    /// Send a method call and wait for the reply, trying again according to the retry policy
+    ///
+    /// Calls that change something are recorded in the audit log, if there is one, once for each
+    /// attempt.
+    fn send(&self, message: Message, context: &str) -> Result<Message, LabeledError> {
+        let message = self.prepare(message)?;
+        let mut audit = self.audit(&message)?;
+        let retry = &self.config.retry;
+        let mut backoff = retry.backoff;
+        let mut attempt = 1;
+        loop {
+            // Keep the original, as a message can only be sent once
+            let copy = message
+                .duplicate()
+                .map_err(|err| self.error(err, context))?;
+            let result = self
+                .conn
+                .channel()
+                .send_with_reply_and_block(copy, self.config.timeout.item);
+            let again = matches!(&result, Err(err) if retry.should_retry(attempt, err));
+            let result = result.map_err(|err| self.call_error(err, &message, context));
+            if let Some(audit) = &mut audit {
+                let outcome = result.as_ref().map(|reply| {
+                    crate::convert::from_message(
+                        reply,
+                        self.config.span,
+                        self.config.u64_style,
+                        None,
+                    )
+                    .map_or_else(
+                        |err| Value::string(err, self.config.span),
+                        |values| Value::list(values, self.config.span),
+                    )
+                });
+                self.record_audit(audit, outcome)?;
+            }
+            if !again {
+                return result;
+            }
+            std::thread::sleep(backoff);
+            backoff = backoff.saturating_mul(2);
+            attempt += 1;
+            if let Some(audit) = &mut audit {
+                audit.retry();
+            }
+        }
+    }
+
+    /// The error for a method call that failed, with help for the failures that can be fixed
+    fn call_error(&self, err: dbus::Error, message: &Message, context: &str) -> LabeledError {
+        if !self.config.interactive_auth && err.name() == Some(INTERACTIVE_AUTHORIZATION_REQUIRED) {
+            self.error(err, context).with_help(
+                "the service needs to ask for authorization, \
+                 try again with --interactive-auth",
             )
-        })?;
-        Ok(DbusClient {
-            config,
-            conn: channel,
+        } else if carries_fds(message) {
+            self.error(err, context).with_help(FD_PASSING_HELP)
+        } else {
+            self.error(err, context)
+        }
+    }
+
+    /// Send a method call without asking for a reply, returning once it has been written out
+    fn send_no_reply(&self, message: Message, context: &str) -> Result<(), LabeledError> {
+        let mut message = self.prepare(message)?;
+        message.set_no_reply(true);
+        let mut audit = self.audit(&message)?;
+
+        let channel = self.conn.channel();
+        let carries_fds = carries_fds(&message);
+        let result = channel
+            .send(message)
+            .map(|_| channel.flush())
+            .map_err(|()| self.send_failed(carries_fds, context));
+        if let Some(audit) = &mut audit {
+            let outcome = result.as_ref().map(|()| Value::nothing(self.config.span));
+            self.record_audit(audit, outcome)?;
+        }
+        result
+    }
+
+    /// Wait until the name has an owner on the bus, by watching `NameOwnerChanged`
+    fn wait_for_name(&self, name: &str, wait: &Spanned<Duration>) -> Result<(), LabeledError> {
+        let context = "while waiting for a name to appear on the bus";
+        if self.conn.unique_name().is_none() || name == "org.freedesktop.DBus" {
+            // Only a bus has names to wait for
+            return Ok(());
+        }
+
+        // Signals are read off the queue directly, so they need a connection of their own
+        let conn = self.conn.open_another()?;
+        let channel = conn.channel();
+        let call_bus = |method: &str, arg: &str| {
+            let message = Message::new_method_call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                method,
+            )
+            .map_err(|err| self.error(err, context))?
+            .append1(arg);
+            channel
+                .send_with_reply_and_block(message, self.config.timeout.item)
+                .map_err(|err| self.error(err, context))
+        };
+
+        // Subscribe before checking, so that the name can't appear unnoticed in between
+        let rule = format!(
+            "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',\
+             member='NameOwnerChanged',arg0='{name}'"
+        );
+        call_bus("AddMatch", &rule)?;
+        call_bus("NameHasOwner", name).and_then(|resp| {
+            if resp.get1() == Some(true) {
+                return Ok(());
+            }
+            let deadline = Instant::now() + wait.item;
+            loop {
+                while let Some(message) = channel.pop_message() {
+                    if message.member().as_deref() == Some("NameOwnerChanged")
+                        && message
+                            .read3::<String, String, String>()
+                            .is_ok_and(|(changed, _, new)| changed == name && !new.is_empty())
+                    {
+                        return Ok(());
+                    }
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(LabeledError::new(format!(
+                        "{name} didn't appear on the bus in time"
+                    ))
+                    .with_label("waited for this long", wait.span)
+                    .with_help("check that the service is installed and starting up"));
+                }
+                channel
+                    .read_write(Some(remaining))
+                    .map_err(|()| self.error("the connection was closed", context))?;
+            }
         })
     }
 
-    fn error(&self, err: impl std::fmt::Display, msg: impl std::fmt::Display) -> LabeledError {
-        LabeledError::new(err.to_string()).with_label(msg.to_string(), self.config.span)
+    /// Send all of the method calls before waiting for any of the replies
+    ///
+    /// The outcome of each call is returned in order, as soon as it is available. Introspection
+    /// is only done once per object. Calls can be given as errors (e.g. from parsing them), which
+    /// are passed through in their place.
+    ///
+    /// The calls are sent on a connection of their own, as the replies are read off its queue
+    /// directly.
+    pub fn batch(
+        self,
+        calls: Vec<Result<BatchCall, LabeledError>>,
+        span: Span,
+    ) -> Result<BatchReplies, LabeledError> {
+        let context = "while calling a D-Bus method";
+        let conn = self.conn.open_another()?;
+
+        // Prepare every message first, as introspection and waiting for names can't be done while
+        // replies are outstanding
+        let prepared = calls
+            .into_iter()
+            .map(|call| {
+                let call = match call {
+                    Ok(call) => call,
+                    Err(err) => return (batch_description(None, span), Err(err)),
+                };
+                let description = batch_description(Some(&call), span);
+                let message = self.resolve_dest(&call.dest).and_then(|dest| {
+                    let introspect = || self.introspect(&dest, &call.object);
+                    let (message, no_reply) = self.method_call_message(
+                        &dest,
+                        &call.object,
+                        &call.interface,
+                        &call.method,
+                        call.signature.as_ref(),
+                        &call.args,
+                        introspect,
+                    )?;
+                    let mut message = self.prepare(message)?;
+                    message.set_no_reply(no_reply);
+                    let audit = self.audit(&message)?;
+                    Ok((message, audit))
+                });
+                (description, message)
+            })
+            .collect::<Vec<_>>();
+
+        let channel = conn.channel();
+        let mut waiting = HashMap::new();
+        let rows = prepared
+            .into_iter()
+            .enumerate()
+            .map(|(index, (description, message))| {
+                let (message, audit) = match message {
+                    Ok((message, audit)) => (Ok(message), audit),
+                    Err(err) => (Err(err), None),
+                };
+                let outcome = match message {
+                    Ok(message) => {
+                        let no_reply = message.get_no_reply();
+                        let carries_fds = carries_fds(&message);
+                        match channel.send(message) {
+                            Ok(_) if no_reply => Some(Ok(Value::nothing(span))),
+                            Ok(serial) => {
+                                waiting.insert(serial, index);
+                                None
+                            }
+                            Err(()) => Some(Err(self.send_failed(carries_fds, context))),
+                        }
+                    }
+                    Err(err) => Some(Err(err)),
+                };
+                BatchRow {
+                    description,
+                    outcome,
+                    audit,
+                    deadline: Instant::now() + self.config.timeout.item,
+                }
+            })
+            .collect();
+        channel.flush();
+
+        Ok(BatchReplies {
+            client: self,
+            conn,
+            rows,
+            waiting,
+            next: 0,
+            span,
+        })
+    }
+
+    /// Make the same method call over and over, keeping up to `concurrency` of them waiting for a
+    /// reply at once, and measure how long each one takes
+    ///
+    /// Stops sending once the count or duration is reached, or when interrupted. The calls are
+    /// made on a separate connection, so that other calls in flight aren't disturbed.
+    ///
+    /// Methods that change something are refused if there is an audit log, rather than
+    /// recording every call.
+    pub fn bench(
+        &self,
+        call: &BatchCall,
+        options: &BenchOptions,
+        signals: &Signals,
+    ) -> Result<BenchReport, LabeledError> {
+        let context = "while benchmarking a D-Bus method";
+        if let Some(path) = &self.config.audit_log {
+            if !SafetyPolicy::is_read(&call.interface.item, &call.method.item) {
+                return Err(LabeledError::new(
+                    "Can't benchmark a method that changes something with the audit log on",
+                )
+                .with_label("this method isn't a read", call.method.span)
+                .with_label("every call would have to be recorded here", path.span)
+                .with_help("benchmark it somewhere without an audit_log in the plugin config"));
+            }
+        }
+        let dest = &self.resolve_dest(&call.dest)?;
+        let (message, no_reply) = self.method_call_message(
+            dest,
+            &call.object,
+            &call.interface,
+            &call.method,
+            call.signature.as_ref(),
+            &call.args,
+            || self.introspect(dest, &call.object),
+        )?;
+        if no_reply {
+            return Err(self
+                .error(
+                    "can't measure the latency of a call without a reply",
+                    context,
+                )
+                .with_help("remove --no-reply, or pick a method that isn't annotated NoReply"));
+        }
+        let message = self.prepare(message)?;
+
+        // The replies are read off the queue directly, so they need a connection of their own
+        let conn = self.conn.open_another()?;
+        let channel = conn.channel();
+        let timeout = self.config.timeout.item;
+        let closed = || self.send_failed(carries_fds(&message), context);
+        let start = Instant::now();
+        let count = match (options.count, options.duration) {
+            (None, None) => Some(BenchOptions::DEFAULT_COUNT),
+            (count, _) => count,
+        };
+        let end = options.duration.map(|duration| start + duration);
+
+        let mut report = BenchReport::default();
+        // When each call that is waiting for a reply was sent, by serial
+        let mut waiting: HashMap<u32, Instant> = HashMap::new();
+        let mut sent = 0;
+        loop {
+            while waiting.len() < options.concurrency.max(1)
+                && count.is_none_or(|count| sent < count)
+                && end.is_none_or(|end| Instant::now() < end)
+                && !signals.interrupted()
+            {
+                let copy = message
+                    .duplicate()
+                    .map_err(|err| self.error(err, context))?;
+                let serial = channel.send(copy).map_err(|()| closed())?;
+                waiting.insert(serial, Instant::now());
+                sent += 1;
+            }
+            if waiting.is_empty() {
+                break;
+            }
+            channel.flush();
+
+            if let Some(mut reply) = channel.pop_message() {
+                let sent_at = reply
+                    .get_reply_serial()
+                    .and_then(|serial| waiting.remove(&serial));
+                if let Some(sent_at) = sent_at {
+                    match reply.as_result() {
+                        Ok(_) => report.add_success(sent_at.elapsed()),
+                        Err(err) => report.add_error(err.name().unwrap_or("unknown error")),
+                    }
+                }
+                continue;
+            }
+
+            // Give up on the calls that have waited too long, like libdbus would
+            let now = Instant::now();
+            let oldest = waiting.values().min().copied().unwrap_or(now);
+            let remaining = (oldest + timeout).saturating_duration_since(now);
+            if remaining.is_zero() {
+                waiting.retain(|_, sent_at| {
+                    let expired = now.duration_since(*sent_at) >= timeout;
+                    if expired {
+                        report.add_error("org.freedesktop.DBus.Error.NoReply");
+                    }
+                    !expired
+                });
+            } else {
+                channel.read_write(Some(remaining)).map_err(|()| closed())?;
+            }
+        }
+        report.finish(start.elapsed());
+        Ok(report)
     }
 
     /// Introspect a D-Bus object
+    ///
+    /// The result is kept for as long as the client, so repeated calls on the same object only
+    /// introspect it once.
     pub fn introspect(
         &self,
         dest: &Spanned<String>,
         object: &Spanned<String>,
+    ) -> Result<Node, LabeledError> {
+        let dest = &self.resolve_dest(dest)?;
+        let key = (dest.item.clone(), object.item.clone());
+        if let Some(node) = self.introspection.lock().unwrap().get(&key) {
+            return node.clone();
+        }
+        let node = self.introspect_uncached(dest, object);
+        self.introspection.lock().unwrap().insert(key, node.clone());
+        node
+    }
+
+    fn introspect_uncached(
+        &self,
+        dest: &Spanned<String>,
+        object: &Spanned<String>,
     ) -> Result<Node, LabeledError> {
         let context = "while introspecting a D-Bus method";
         let valid_dest = validate_with!(dbus::strings::BusName, dest)?;
@@ -76,10 +656,7 @@ impl DbusClient {
         .map_err(|err| self.error(err, context))?;
 
         // Send and get the response
-        let resp = self
-            .conn
-            .send_with_reply_and_block(message, self.config.timeout.item)
-            .map_err(|err| self.error(err, context))?;
+        let resp = self.send(message, context)?;
 
         // Parse it to a Node
         let xml: &str = resp
@@ -89,27 +666,28 @@ impl DbusClient {
         Node::from_xml(xml).map_err(|err| self.error(err, context))
     }
 
-    /// Try to use introspection to get the signature of a method
+    /// Try to use introspection to get the signature of a method, and whether it is annotated as
+    /// never replying
     fn get_method_signature_by_introspection(
         &self,
-        dest: &Spanned<String>,
-        object: &Spanned<String>,
+        node: &Node,
         interface: &Spanned<String>,
         method: &Spanned<String>,
-    ) -> Result<Vec<DbusType>, LabeledError> {
-        let node = self.introspect(dest, object)?;
-
+    ) -> Result<(Vec<DbusType>, bool), LabeledError> {
         if let Some(sig) = node.get_method_args_signature(&interface.item, &method.item) {
-            DbusType::parse_all(&sig).map_err(|err| {
-                LabeledError::new(format!(
-                    "while getting interface {:?} method {:?} signature: {}",
-                    interface.item, method.item, err
-                ))
-                .with_label(
-                    "try running with --no-introspect or --signature",
-                    self.config.span,
-                )
-            })
+            let no_reply = node.is_method_no_reply(&interface.item, &method.item);
+            DbusType::parse_all(&sig)
+                .map(|sig| (sig, no_reply))
+                .map_err(|err| {
+                    LabeledError::new(format!(
+                        "while getting interface {:?} method {:?} signature: {}",
+                        interface.item, method.item, err
+                    ))
+                    .with_label(
+                        "try running with --no-introspect or --signature",
+                        self.config.span,
+                    )
+                })
         } else {
             Err(LabeledError::new(format!(
                 "Method {:?} not found on {:?}",
@@ -163,12 +741,54 @@ impl DbusClient {
         args: &[Value],
     ) -> Result<Vec<Value>, LabeledError> {
         let context = "while calling a D-Bus method";
+        let dest = &self.resolve_dest(dest)?;
+
+        let (message, no_reply) =
+            self.method_call_message(dest, object, interface, method, signature, args, || {
+                self.introspect(dest, object)
+            })?;
+
+        // Don't wait for a response if there won't be one
+        if no_reply {
+            self.send_no_reply(message, context)?;
+            return Ok(vec![]);
+        }
+
+        // Send it on the channel and get the response
+        let resp = self.send(message, context)?;
+
+        crate::convert::from_message(
+            &resp,
+            self.config.span,
+            self.config.u64_style,
+            Some(&self.fds),
+        )
+        .map_err(|err| self.error(err, context))
+    }
+
+    /// Construct the message for a method call to a resolved destination, returning whether a
+    /// reply should be waited for
+    ///
+    /// `introspect` is used to find the signature if it isn't given.
+    #[allow(clippy::too_many_arguments)]
+    fn method_call_message(
+        &self,
+        dest: &Spanned<String>,
+        object: &Spanned<String>,
+        interface: &Spanned<String>,
+        method: &Spanned<String>,
+        signature: Option<&Spanned<String>>,
+        args: &[Value],
+        introspect: impl FnOnce() -> Result<Node, LabeledError>,
+    ) -> Result<(Message, bool), LabeledError> {
+        let context = "while calling a D-Bus method";
 
         // Validate inputs before sending to the dbus lib so we don't panic
         let valid_dest = validate_with!(dbus::strings::BusName, dest)?;
         let valid_object = validate_with!(dbus::strings::Path, object)?;
         let valid_interface = validate_with!(dbus::strings::Interface, interface)?;
         let valid_method = validate_with!(dbus::strings::Member, method)?;
+        self.check_safety(&interface.item, method)?;
 
         // Parse the signature
         let mut valid_signature = signature
@@ -179,13 +799,18 @@ impl DbusClient {
             })
             .transpose()?;
 
+        let mut no_reply = self.config.no_reply;
+
         // If not provided, try introspection (unless disabled)
         if valid_signature.is_none() && self.config.introspect {
-            match self.get_method_signature_by_introspection(dest, object, interface, method) {
-                Ok(sig) => {
+            match introspect().and_then(|node| {
+                self.get_method_signature_by_introspection(&node, interface, method)
+            }) {
+                Ok((sig, annotated_no_reply)) => {
                     valid_signature = Some(sig);
+                    no_reply |= annotated_no_reply;
                 }
-                Err(err) => {
+                Err(err) if self.config.warnings => {
                     eprintln!(
                         "Warning: D-Bus introspection failed on {:?}. \
                         Use `--no-introspect` or pass `--signature` to silence this warning. \
@@ -193,6 +818,7 @@ impl DbusClient {
                         object.item, err
                     );
                 }
+                Err(_) => (),
             }
         }
 
@@ -216,18 +842,20 @@ impl DbusClient {
             .flatten()
             .map(Some)
             .chain(std::iter::repeat(None));
-        for (val, sig) in args.iter().zip(sigs_iter) {
-            message = message.append1(to_message_item(val, sig)?);
+        for (index, (val, sig)) in args.iter().zip(sigs_iter).enumerate() {
+            let item = to_message_item(val, sig, self.fd_source()).map_err(|err| {
+                self.config.redaction.redact_arg_error(
+                    &interface.item,
+                    &method.item,
+                    args,
+                    index,
+                    err,
+                )
+            })?;
+            message = message.append1(item);
         }
 
-        // Send it on the channel and get the response
-        let resp = self
-            .conn
-            .send_with_reply_and_block(message, self.config.timeout.item)
-            .map_err(|err| self.error(err, context))?;
-
-        crate::convert::from_message(&resp, self.config.span)
-            .map_err(|err| self.error(err, context))
+        Ok((message, no_reply))
     }
 
     /// Get a D-Bus property from the given object
@@ -301,6 +929,14 @@ impl DbusClient {
         value: &Value,
     ) -> Result<(), LabeledError> {
         let context = "while setting a D-Bus property";
+        self.check_safety(
+            "org.freedesktop.DBus.Properties",
+            &Spanned {
+                item: "Set".into(),
+                span: property.span,
+            },
+        )?;
+        let dest = &self.resolve_dest(dest)?;
 
         // Validate inputs before sending to the dbus lib so we don't panic
         let valid_dest = validate_with!(dbus::strings::BusName, dest)?;
@@ -321,7 +957,7 @@ impl DbusClient {
                 Ok(sig) => {
                     valid_signature = Some(sig);
                 }
-                Err(err) => {
+                Err(err) if self.config.warnings => {
                     eprintln!(
                         "Warning: D-Bus introspection failed on {:?}. \
                         Use `--no-introspect` or pass `--signature` to silence this warning. \
@@ -329,6 +965,7 @@ impl DbusClient {
                         object.item, err
                     );
                 }
+                Err(_) => (),
             }
         }
 
@@ -355,32 +992,197 @@ impl DbusClient {
         .append2(&interface.item, &property.item)
         .append1(
             // Box it in a variant as required for property setting
-            MessageItem::Variant(Box::new(to_message_item(
-                value,
-                valid_signature.as_ref().map(|s| &s[0]),
-            )?)),
+            MessageItem::Variant(Box::new(
+                to_message_item(
+                    value,
+                    valid_signature.as_ref().map(|s| &s[0]),
+                    self.fd_source(),
+                )
+                .map_err(|err| {
+                    let args = [
+                        Value::string(&interface.item, interface.span),
+                        Value::string(&property.item, property.span),
+                        value.clone(),
+                    ];
+                    self.config.redaction.redact_arg_error(
+                        "org.freedesktop.DBus.Properties",
+                        "Set",
+                        &args,
+                        2,
+                        err,
+                    )
+                })?,
+            )),
         );
 
         // Send it on the channel and get the response
-        self.conn
-            .send_with_reply_and_block(message, self.config.timeout.item)
-            .map_err(|err| self.error(err, context))?;
+        self.send(message, context)?;
 
         Ok(())
     }
 
+    /// Describe our connection and the bus it's to
+    ///
+    /// Details that the server doesn't provide (e.g. a peer that isn't a bus) are null.
+    pub fn whoami(&self, span: Span) -> Result<Value, LabeledError> {
+        let context = "while describing the D-Bus connection";
+        let unique_name = self.conn.unique_name();
+
+        let call_bus = |interface: &str, method: &str, args: &[&str]| {
+            let mut message = Message::new_method_call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                interface,
+                method,
+            )
+            .map_err(|err| self.error(err, context))?;
+            if unique_name.is_none() {
+                // Not a bus, so the peer answers for itself
+                message.set_destination(None);
+            }
+            for arg in args {
+                message = message.append1(arg);
+            }
+            self.conn
+                .channel()
+                .send_with_reply_and_block(message, self.config.timeout.item)
+                .map_err(|err| self.error(err, context))
+        };
+        let get_string = |interface: &str, method: &str| {
+            call_bus(interface, method, &[])
+                .ok()
+                .and_then(|reply| reply.get1::<String>())
+                .map(|value| Value::string(value, span))
+                .unwrap_or_default()
+        };
+        let get_bus_property = |property: &str| {
+            call_bus(
+                "org.freedesktop.DBus.Properties",
+                "Get",
+                &["org.freedesktop.DBus", property],
+            )
+            .ok()
+            .and_then(|reply| reply.get1::<dbus::arg::Variant<Vec<String>>>())
+            .map(|values| {
+                Value::list(
+                    values
+                        .0
+                        .into_iter()
+                        .map(|v| Value::string(v, span))
+                        .collect(),
+                    span,
+                )
+            })
+            .unwrap_or_default()
+        };
+
+        let machine_id = get_string("org.freedesktop.DBus.Peer", "GetMachineId");
+        let Some(unique_name) = unique_name else {
+            return Ok(Value::record(
+                record! {
+                    "unique_name" => Value::nothing(span),
+                    "address" => Value::string(self.conn.address(), span),
+                    "bus_id" => Value::nothing(span),
+                    "machine_id" => machine_id,
+                    "features" => Value::nothing(span),
+                    "interfaces" => Value::nothing(span),
+                    "owned_names" => Value::nothing(span),
+                },
+                span,
+            ));
+        };
+
+        // The bus doesn't have a way to list the names owned by a connection, so check them all
+        let owned_names = self
+            .list(None)?
+            .into_iter()
+            .filter(|name| !name.starts_with(':'))
+            .filter(|name| {
+                call_bus("org.freedesktop.DBus", "GetNameOwner", &[name])
+                    .ok()
+                    .and_then(|reply| reply.get1::<String>())
+                    .is_some_and(|owner| owner == unique_name)
+            })
+            .map(|name| Value::string(name, span))
+            .collect();
+
+        Ok(Value::record(
+            record! {
+                "unique_name" => Value::string(unique_name, span),
+                "address" => Value::string(self.conn.address(), span),
+                "bus_id" => get_string("org.freedesktop.DBus", "GetId"),
+                "machine_id" => machine_id,
+                "features" => get_bus_property("Features"),
+                "interfaces" => get_bus_property("Interfaces"),
+                "owned_names" => Value::list(owned_names, span),
+            },
+            span,
+        ))
+    }
+
+    /// Ask the bus to start the service for a name, returning whether it had to be started (as
+    /// opposed to already running)
+    pub fn start_service(&self, name: &Spanned<String>) -> Result<bool, LabeledError> {
+        let context = "while starting a D-Bus service";
+        self.check_safety(
+            "org.freedesktop.DBus",
+            &Spanned {
+                item: "StartServiceByName".into(),
+                span: name.span,
+            },
+        )?;
+        let name = &self.resolve_dest(name)?;
+        let valid_name = validate_with!(dbus::strings::BusName, name)?;
+
+        let message = Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "StartServiceByName",
+        )
+        .map_err(|err| self.error(err, context))?
+        .append2(&*valid_name, 0u32);
+
+        let resp = self.send(message, context)?;
+        match resp.read1::<u32>() {
+            // DBUS_START_REPLY_SUCCESS
+            Ok(1) => Ok(true),
+            // DBUS_START_REPLY_ALREADY_RUNNING
+            Ok(2) => Ok(false),
+            Ok(other) => Err(self.error(format!("unexpected reply {other}"), context)),
+            Err(err) => Err(self.error(err, context)),
+        }
+    }
+
     pub fn list(&self, pattern: Option<&Pattern>) -> Result<Vec<String>, LabeledError> {
-        let context = "while listing D-Bus connection names";
+        self.list_with("ListNames", pattern, "while listing D-Bus connection names")
+    }
+
+    /// List the names that the bus can start services for
+    pub fn list_activatable(&self, pattern: Option<&Pattern>) -> Result<Vec<String>, LabeledError> {
+        self.list_with(
+            "ListActivatableNames",
+            pattern,
+            "while listing activatable D-Bus names",
+        )
+    }
 
+    fn list_with(
+        &self,
+        method: &str,
+        pattern: Option<&Pattern>,
+        context: &str,
+    ) -> Result<Vec<String>, LabeledError> {
         let message = Message::new_method_call(
             "org.freedesktop.DBus",
             "/org/freedesktop/DBus",
             "org.freedesktop.DBus",
-            "ListNames",
+            method,
         )
         .map_err(|err| self.error(err, context))?;
 
         self.conn
+            .channel()
             .send_with_reply_and_block(message, self.config.timeout.item)
             .map_err(|err| self.error(err, context))
             .and_then(|reply| reply.read1().map_err(|err| self.error(err, context)))
@@ -397,3 +1199,623 @@ impl DbusClient {
             })
     }
 }
+
+/// Identify a call in the results of a batch, even if it couldn't be made
+fn batch_description(call: Option<&BatchCall>, span: Span) -> Record {
+    let field = |get: fn(&BatchCall) -> &Spanned<String>| {
+        call.map(|call| Value::string(&get(call).item, span))
+            .unwrap_or_default()
+    };
+    record! {
+        "dest" => field(|call| &call.dest),
+        "object" => field(|call| &call.object),
+        "interface" => field(|call| &call.interface),
+        "method" => field(|call| &call.method),
+    }
+}
+
+struct BatchRow {
+    description: Record,
+    outcome: Option<Result<Value, LabeledError>>,
+    audit: Option<AuditEntry>,
+    deadline: Instant,
+}
+
+/// The results of [`DbusClient::batch`], in the order the calls were given
+pub struct BatchReplies {
+    client: DbusClient,
+    /// The connection the calls were sent on, which nothing else uses
+    conn: DbusConnection,
+    rows: Vec<BatchRow>,
+    /// The index of the row for each serial that we're waiting for a reply to
+    waiting: HashMap<u32, usize>,
+    next: usize,
+    span: Span,
+}
+
+impl BatchReplies {
+    /// Wait until the outcome of the next row is known
+    fn wait_for_next(&mut self) {
+        let context = "while calling a D-Bus method";
+        let channel = self.conn.channel();
+        while self.rows[self.next].outcome.is_none() {
+            if let Some(mut message) = channel.pop_message() {
+                let index = message
+                    .get_reply_serial()
+                    .and_then(|serial| self.waiting.remove(&serial));
+                if let Some(index) = index {
+                    let outcome = message
+                        .as_result()
+                        .map_err(|err| self.client.error(err, context))
+                        .and_then(|reply| {
+                            crate::convert::from_message(
+                                reply,
+                                self.span,
+                                self.client.config.u64_style,
+                                Some(&self.client.fds),
+                            )
+                            .map_err(|err| self.client.error(err, context))
+                        })
+                        .map(|values| match values.len() {
+                            0 => Value::nothing(self.span),
+                            1 => values.into_iter().nth(0).unwrap(),
+                            _ => Value::list(values, self.span),
+                        });
+                    self.rows[index].outcome = Some(outcome);
+                }
+                continue;
+            }
+
+            let row = &mut self.rows[self.next];
+            let remaining = row.deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                row.outcome = Some(Err(self
+                    .client
+                    .error("Did not receive a reply in time", context)));
+                let next = self.next;
+                self.waiting.retain(|_, index| *index != next);
+            } else if channel.read_write(Some(remaining)).is_err() {
+                row.outcome = Some(Err(self.client.error("the connection was closed", context)));
+            }
+        }
+    }
+}
+
+impl Iterator for BatchReplies {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.next >= self.rows.len() {
+            return None;
+        }
+        self.wait_for_next();
+
+        let span = self.span;
+        let row = &mut self.rows[self.next];
+        self.next += 1;
+        let mut outcome = row.outcome.take()?;
+        if let Some(mut audit) = row.audit.take() {
+            if let Err(err) = self
+                .client
+                .record_audit(&mut audit, outcome.as_ref().cloned())
+            {
+                outcome = Err(err);
+            }
+        }
+        let (status, result, error) = match outcome {
+            Ok(value) => ("ok", value, Value::nothing(span)),
+            Err(err) => ("error", Value::nothing(span), Value::string(err.msg, span)),
+        };
+        let mut record = std::mem::take(&mut row.description);
+        record.push("status", Value::string(status, span));
+        record.push("result", result);
+        record.push("error", error);
+        Some(Value::record(record, span))
+    }
+}
+
+impl Drop for BatchReplies {
+    /// Record the calls that were sent but never reached in the audit log, so that dropping the
+    /// stream early doesn't leave them out
+    fn drop(&mut self) {
+        let not_awaited = LabeledError::new("The reply wasn't waited for");
+        for row in &mut self.rows[self.next..] {
+            if let Some(mut audit) = row.audit.take() {
+                let outcome = match &row.outcome {
+                    Some(Ok(value)) => Ok(value.clone()),
+                    Some(Err(err)) => Err(err),
+                    None => Err(&not_awaited),
+                };
+                let _ = self.client.record_audit(&mut audit, outcome);
+            }
+        }
+    }
+}
+
+const INTERACTIVE_AUTHORIZATION_REQUIRED: &str =
+    "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired";
+
+extern "C" {
+    fn dbus_message_set_allow_interactive_authorization(message: *mut c_void, allow: u32);
+    #[cfg(test)]
+    fn dbus_message_get_allow_interactive_authorization(message: *mut c_void) -> u32;
+}
+
+/// The libdbus message that a [`Message`] wraps
+///
+/// The dbus crate keeps it private, but it's the only thing in a `Message`.
+fn message_ptr(message: &Message) -> *mut c_void {
+    const _: () = assert!(size_of::<Message>() == size_of::<*mut c_void>());
+    // SAFETY: the size matches, so the message is just the pointer, which stays valid as long as
+    // the message does
+    unsafe { *(message as *const Message).cast::<*mut c_void>() }
+}
+
+/// Set the ALLOW_INTERACTIVE_AUTHORIZATION flag on a method call, which the dbus crate has no
+/// setter for
+fn allow_interactive_authorization(message: &mut Message) {
+    // SAFETY: the message is valid, and we have it mutably
+    unsafe { dbus_message_set_allow_interactive_authorization(message_ptr(message), 1) }
+}
+
+pub const FD_PASSING_HELP: &str =
+    "file descriptors (type `h`) can only be sent over a unix socket \
+     to a bus or peer that supports passing them";
+
+/// Whether a message has file descriptors in it
+fn carries_fds(message: &Message) -> bool {
+    crate::audit::signature(message).contains('h')
+}
+
+/// A test bus with a connection to it, shared by the tests of the client's features
+#[cfg(test)]
+pub struct TestClient {
+    pub bus: crate::test_bus::TestBus,
+    pub config: DbusClientConfig,
+    pub conn: Arc<DbusConnection>,
+}
+
+#[cfg(test)]
+impl TestClient {
+    /// Start a test bus and connect to it, with `configure` changing the default config
+    pub fn start(configure: impl FnOnce(&mut DbusClientConfig)) -> TestClient {
+        let bus = crate::test_bus::TestBus::start().unwrap();
+        let mut config =
+            DbusClientConfig::for_test(crate::config::DbusBusChoice::Bus(bus.address().into()));
+        configure(&mut config);
+        let conn = Arc::new(DbusConnection::open(&config.bus_choice, None).unwrap());
+        TestClient { bus, config, conn }
+    }
+
+    /// A client on the connection
+    pub fn client(&self) -> DbusClient {
+        self.client_with(|_| ())
+    }
+
+    /// A client on the connection, with `configure` changing the config further
+    pub fn client_with(&self, configure: impl FnOnce(&mut DbusClientConfig)) -> DbusClient {
+        let mut config = self.config.clone();
+        configure(&mut config);
+        DbusClient::new(config, self.conn.clone())
+    }
+
+    /// Own a name on the bus with the connection
+    pub fn request_name(&self, name: &str) {
+        let message = Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+        )
+        .unwrap()
+        .append2(name, 0u32);
+        self.conn
+            .channel()
+            .send_with_reply_and_block(message, Duration::from_secs(5))
+            .unwrap();
+    }
+
+    /// Start a service on the bus with a connection of its own, returning its unique name and
+    /// what it returns when it's done
+    pub fn serve<T: Send + 'static>(
+        &self,
+        service: impl FnOnce(dbus::channel::Channel) -> T + Send + 'static,
+    ) -> (String, std::thread::JoinHandle<T>) {
+        let mut channel = dbus::channel::Channel::open_private(self.bus.address()).unwrap();
+        channel.register().unwrap();
+        let name = channel.unique_name().unwrap().to_owned();
+        (name, std::thread::spawn(move || service(channel)))
+    }
+}
+
+/// Wait for the next method call to a service
+#[cfg(test)]
+pub fn next_call(channel: &dbus::channel::Channel) -> Message {
+    loop {
+        match channel.pop_message() {
+            Some(message) if message.msg_type() == dbus::MessageType::MethodCall => return message,
+            Some(_) => (),
+            None => channel
+                .read_write(Some(Duration::from_secs(5)))
+                .expect("the test bus went away"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn spanned(item: &str) -> Spanned<String> {
+    Spanned {
+        item: item.to_owned(),
+        span: Span::test_data(),
+    }
+}
+
+/// Call a method of the bus itself
+#[cfg(test)]
+pub fn bus_call(
+    client: &DbusClient,
+    interface: &str,
+    method: &str,
+    signature: &str,
+    args: &[Value],
+) -> Result<Vec<Value>, LabeledError> {
+    client.call(
+        &spanned("org.freedesktop.DBus"),
+        &spanned("/org/freedesktop/DBus"),
+        &spanned(interface),
+        &spanned(method),
+        Some(&spanned(signature)),
+        args,
+    )
+}
+
+/// Keep pinging the bus for a while, e.g. to check that replies aren't taken by something else
+/// reading from the same connection
+#[cfg(test)]
+pub fn ping_for(client: &DbusClient, duration: Duration) -> Result<(), LabeledError> {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        bus_call(client, "org.freedesktop.DBus.Peer", "Ping", "", &[])?;
+    }
+    Ok(())
+}
+
+/// Start a service on the bus after a delay, which answers a single method call with "pong"
+#[cfg(test)]
+fn serve_late(address: &str, name: &'static str, delay: Duration) -> std::thread::JoinHandle<()> {
+    let address = address.to_owned();
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        let mut channel = dbus::channel::Channel::open_private(&address).unwrap();
+        channel.register().unwrap();
+        let message = Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+        )
+        .unwrap()
+        .append2(name, 0u32);
+        channel
+            .send_with_reply_and_block(message, Duration::from_secs(5))
+            .unwrap();
+        // Give up eventually if nothing calls
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            channel
+                .read_write(Some(Duration::from_millis(100)))
+                .unwrap();
+            if let Some(call) = channel.pop_message() {
+                if call.msg_type() == dbus::MessageType::MethodCall {
+                    channel.send(call.method_return().append1("pong")).unwrap();
+                    channel.flush();
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[test]
+fn test_resolve_dest() {
+    let test = TestClient::start(|config| {
+        config
+            .aliases
+            .insert("player".into(), "org.mpris.MediaPlayer2.*".into());
+    });
+    let client = test.client();
+
+    // Not a pattern, so left alone even if it doesn't exist
+    assert_eq!(
+        client
+            .resolve_dest(&spanned("com.example.Foo"))
+            .unwrap()
+            .item,
+        "com.example.Foo"
+    );
+    // Nothing matches yet
+    assert!(client.resolve_dest(&spanned("player")).is_err());
+
+    test.request_name("org.mpris.MediaPlayer2.one");
+    assert_eq!(
+        client.resolve_dest(&spanned("player")).unwrap().item,
+        "org.mpris.MediaPlayer2.one"
+    );
+
+    // Ambiguous
+    test.request_name("org.mpris.MediaPlayer2.two");
+    assert!(client
+        .resolve_dest(&spanned("org.mpris.MediaPlayer2.*"))
+        .is_err());
+    assert_eq!(
+        client
+            .resolve_dest(&spanned("org.mpris.*.t?o"))
+            .unwrap()
+            .item,
+        "org.mpris.MediaPlayer2.two"
+    );
+}
+
+#[test]
+fn test_for_each_matching() {
+    let test = TestClient::start(|_| ());
+    let client = test.client();
+    let span = Span::test_data();
+    test.request_name("org.mpris.MediaPlayer2.one");
+    test.request_name("org.mpris.MediaPlayer2.two");
+
+    let table = client
+        .for_each_matching(&spanned("org.mpris.MediaPlayer2.*"), span, |dest| {
+            if dest.item.ends_with("two") {
+                Err(LabeledError::new("no thanks"))
+            } else {
+                Ok(Value::string(&dest.item, span))
+            }
+        })
+        .unwrap();
+
+    let rows = table.into_list().unwrap();
+    assert_eq!(rows.len(), 2);
+    for row in rows {
+        let row = row.into_record().unwrap();
+        let dest = row.get("dest").unwrap().as_str().unwrap().to_owned();
+        let status = row.get("status").unwrap().as_str().unwrap();
+        if dest.ends_with("one") {
+            assert_eq!(status, "ok");
+            assert_eq!(row.get("result").unwrap().as_str().unwrap(), dest);
+        } else {
+            assert_eq!(status, "error");
+            assert_eq!(row.get("error").unwrap().as_str().unwrap(), "no thanks");
+        }
+    }
+}
+
+#[test]
+fn test_whoami() {
+    let test = TestClient::start(|_| ());
+    test.request_name("com.example.WhoAmI");
+
+    let record = test
+        .client()
+        .whoami(Span::test_data())
+        .unwrap()
+        .into_record()
+        .unwrap();
+    assert_eq!(
+        record.get("unique_name").unwrap().as_str().unwrap(),
+        test.conn.unique_name().unwrap()
+    );
+    assert_eq!(
+        record.get("address").unwrap().as_str().unwrap(),
+        test.bus.address()
+    );
+    // The test bus's id is the guid in its address
+    assert!(test
+        .bus
+        .address()
+        .ends_with(record.get("bus_id").unwrap().as_str().unwrap()));
+    assert_eq!(
+        record.get("owned_names").unwrap(),
+        &Value::test_list(vec![Value::test_string("com.example.WhoAmI")])
+    );
+}
+
+#[test]
+fn test_wait_for_name_and_retry() {
+    let test = TestClient::start(|config| config.introspect = false);
+    let call = |client: &DbusClient, dest: &str| {
+        client.call(
+            &spanned(dest),
+            &spanned("/"),
+            &spanned("com.example.Late"),
+            &spanned("Hello"),
+            Some(&spanned("")),
+            &[],
+        )
+    };
+    let wait_for_name = |duration| Spanned {
+        item: duration,
+        span: Span::test_data(),
+    };
+
+    // Without waiting or retrying, a missing service is an error right away
+    assert!(call(&test.client(), "com.example.Late1").is_err());
+
+    let client = test
+        .client_with(|config| config.wait_for_name = Some(wait_for_name(Duration::from_secs(5))));
+    let other = test.client();
+    let service = serve_late(
+        test.bus.address(),
+        "com.example.Late2",
+        Duration::from_millis(200),
+    );
+    std::thread::scope(|scope| {
+        // Calls made on the same connection while waiting still get their replies
+        let ping = scope.spawn(|| ping_for(&other, Duration::from_millis(150)));
+        assert_eq!(
+            call(&client, "com.example.Late2").unwrap(),
+            vec![Value::test_string("pong")]
+        );
+        ping.join().unwrap().unwrap();
+    });
+    service.join().unwrap();
+
+    let client = test.client_with(|config| {
+        config.retry.attempts = 10;
+        config.retry.backoff = Duration::from_millis(10);
+    });
+    let service = serve_late(
+        test.bus.address(),
+        "com.example.Late3",
+        Duration::from_millis(200),
+    );
+    assert_eq!(
+        call(&client, "com.example.Late3").unwrap(),
+        vec![Value::test_string("pong")]
+    );
+    service.join().unwrap();
+
+    let client = test.client_with(|config| {
+        config.wait_for_name = Some(wait_for_name(Duration::from_millis(100)))
+    });
+    let err = call(&client, "com.example.Never").unwrap_err();
+    assert!(err.msg.contains("didn't appear"), "{}", err.msg);
+}
+
+#[test]
+fn test_activation() {
+    let test = TestClient::start(|config| config.introspect = false);
+    let client = test.client();
+
+    assert_eq!(
+        client.list_activatable(None).unwrap(),
+        vec!["org.freedesktop.DBus"]
+    );
+    assert!(!client
+        .start_service(&spanned("org.freedesktop.DBus"))
+        .unwrap());
+    assert!(client
+        .start_service(&spanned("com.example.Missing"))
+        .is_err());
+
+    // The bus tells us whether it tried to start the destination
+    let call = |client: &DbusClient| {
+        client
+            .call(
+                &spanned("com.example.Missing"),
+                &spanned("/"),
+                &spanned("com.example.Missing"),
+                &spanned("Hello"),
+                None,
+                &[],
+            )
+            .unwrap_err()
+            .msg
+    };
+    assert!(call(&client).contains(".service files"));
+    let client = test.client_with(|config| config.auto_start = false);
+    assert!(call(&client).contains("does not exist"));
+}
+
+#[test]
+fn test_interactive_auth() {
+    let test = TestClient::start(|config| {
+        config.introspect = false;
+        config.cwd = env!("CARGO_MANIFEST_DIR").into();
+    });
+
+    // A service that only answers calls that allow interactive authorization, like polkit, and
+    // says whether it got a file descriptor
+    let (service_name, service) = test.serve(|service| {
+        for _ in 0..3 {
+            let call = next_call(&service);
+            // SAFETY: the message is valid
+            let allowed =
+                unsafe { dbus_message_get_allow_interactive_authorization(message_ptr(&call)) };
+            let reply = if allowed != 0 {
+                call.method_return()
+                    .append1(call.get1::<std::fs::File>().is_some())
+            } else {
+                call.error(
+                    &INTERACTIVE_AUTHORIZATION_REQUIRED.into(),
+                    c"Interactive authentication required",
+                )
+            };
+            service.send(reply).unwrap();
+            service.flush();
+        }
+    });
+    let call = |client: &DbusClient, signature: &str, args: &[Value]| {
+        client.call(
+            &spanned(&service_name),
+            &spanned("/org/freedesktop/systemd1"),
+            &spanned("org.freedesktop.systemd1.Manager"),
+            &spanned("Reboot"),
+            Some(&spanned(signature)),
+            args,
+        )
+    };
+
+    let err = call(&test.client(), "", &[]).unwrap_err();
+    assert!(err.help.unwrap().contains("--interactive-auth"));
+
+    let client = test.client_with(|config| config.interactive_auth = true);
+    assert_eq!(
+        call(&client, "", &[]).unwrap(),
+        vec![Value::test_bool(false)]
+    );
+    // File descriptors survive setting the flag
+    assert_eq!(
+        call(&client, "h", &[Value::test_string("Cargo.toml")]).unwrap(),
+        vec![Value::test_bool(true)]
+    );
+    service.join().unwrap();
+}
+
+#[test]
+fn test_no_reply() {
+    let test = TestClient::start(|config| config.timeout.item = Duration::from_millis(500));
+
+    // A service that never replies to Fire, and reports whether it was asked to
+    let (service_name, service) = test.serve(|service| {
+        let mut fired = vec![];
+        while fired.len() < 2 {
+            let call = next_call(&service);
+            match call.member().as_deref() {
+                Some("Introspect") => {
+                    let xml = r#"<node><interface name="com.example.Fire">
+                        <method name="Fire">
+                            <annotation name="org.freedesktop.DBus.Method.NoReply" value="true"/>
+                        </method>
+                    </interface></node>"#;
+                    service.send(call.method_return().append1(xml)).unwrap();
+                    service.flush();
+                }
+                Some("Fire") => fired.push(call.get_no_reply()),
+                _ => (),
+            }
+        }
+        fired
+    });
+    let call = |client: &DbusClient| {
+        client.call(
+            &spanned(&service_name),
+            &spanned("/"),
+            &spanned("com.example.Fire"),
+            &spanned("Fire"),
+            None,
+            &[],
+        )
+    };
+
+    // Found in the introspection
+    assert_eq!(call(&test.client()).unwrap(), vec![]);
+
+    let client = test.client_with(|config| {
+        config.introspect = false;
+        config.no_reply = true;
+    });
+    assert_eq!(call(&client).unwrap(), vec![]);
+
+    assert_eq!(service.join().unwrap(), vec![true, true]);
+}
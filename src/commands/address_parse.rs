@@ -0,0 +1,80 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{record, Example, LabeledError, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{address::DbusAddress, DbusSignatureUtilExt};
+
+pub struct AddressParse;
+
+impl SimplePluginCommand for AddressParse {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus address parse"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_type(Type::Nothing, Type::Table(vec![].into()))
+            .required(
+                "address",
+                SyntaxShape::String,
+                "The address to parse, possibly a `;`-separated list",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Parse a D-Bus server address"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Returns one row per address in the list, in the order they would be tried when \
+            connecting. Values are unescaped."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "address", "parse", "bus", "validate"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example:
+                "dbus address parse 'unix:path=/run/user/1000/bus;tcp:host=localhost,port=1234'",
+            description: "Parse a list of two addresses",
+            result: Some(Value::test_list(vec![
+                Value::test_record(record!(
+                    "transport" => Value::test_string("unix"),
+                    "params" => Value::test_record(record!(
+                        "path" => Value::test_string("/run/user/1000/bus"),
+                    )),
+                )),
+                Value::test_record(record!(
+                    "transport" => Value::test_string("tcp"),
+                    "params" => Value::test_record(record!(
+                        "host" => Value::test_string("localhost"),
+                        "port" => Value::test_string("1234"),
+                    )),
+                )),
+            ])),
+        }]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let address: Spanned<String> = call.req(0)?;
+        let addresses =
+            DbusAddress::parse_list(&address.item).map_err(|err| err.into_labeled(&address))?;
+        Ok(Value::list(
+            addresses
+                .iter()
+                .map(|address| address.to_value(call.head))
+                .collect(),
+            call.head,
+        ))
+    }
+}
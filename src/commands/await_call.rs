@@ -0,0 +1,76 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Type, Value};
+
+use crate::{handle::DbusHandle, DbusSignatureUtilExt};
+
+pub struct Await;
+
+impl SimplePluginCommand for Await {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus await"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_types(vec![
+                (Type::Any, Type::Any),
+                (Type::List(Type::Any.into()), Type::List(Type::Any.into())),
+            ])
+    }
+
+    fn description(&self) -> &str {
+        "Wait for calls started by `dbus call --async` and get their results"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Takes a pending call, or a list of them, and returns the result of each in the same \
+            shape. Each call is subject to the timeout it was started with, and waiting gives up \
+            a little after the calls could have taken, or on Ctrl-C."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "async", "wait", "result", "parallel", "pending"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "let call = dbus call --async --dest=org.freedesktop.DBus \
+                /org/freedesktop/DBus org.freedesktop.DBus GetId; $call | dbus await",
+            description: "Get the result of a call started earlier",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let await_one = |value: &Value| {
+            let handle = value
+                .as_custom_value()
+                .ok()
+                .and_then(|custom| custom.as_any().downcast_ref::<DbusHandle>());
+            match handle
+                .and_then(|handle| plugin.await_call(handle, engine.signals(), value.span()))
+            {
+                Some(result) => result,
+                None => Err(LabeledError::new("Expected a pending D-Bus call")
+                    .with_label("this should come from `dbus call --async`", value.span())),
+            }
+        };
+
+        match input {
+            Value::List { vals, .. } => Ok(Value::list(
+                vals.iter().map(await_one).collect::<Result<_, _>>()?,
+                call.head,
+            )),
+            _ => await_one(input),
+        }
+    }
+}
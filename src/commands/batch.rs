@@ -0,0 +1,207 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    Example, LabeledError, ListStream, PipelineData, Signature, Spanned, Type, Value,
+};
+
+use crate::{client::BatchCall, config::DbusClientConfig, DbusSignatureUtilExt};
+
+pub struct Batch;
+
+impl PluginCommand for Batch {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus batch"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .accepts_timeout()
+            .accepts_unsafe()
+            .accepts_no_auto_start()
+            .accepts_interactive_auth()
+            .switch(
+                "no-introspect",
+                "Don't use introspection to determine the argument signatures of rows \
+                 without a signature",
+                None,
+            )
+            .input_output_types(vec![
+                (Type::table(), Type::table()),
+                (Type::List(Type::Any.into()), Type::table()),
+            ])
+    }
+
+    fn description(&self) -> &str {
+        "Make many method calls at once, from a table"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Each row has the columns dest, object, interface and method, and optionally args (a \
+            list) and signature, as for `dbus call`. All of the calls are sent before waiting \
+            for any replies, which is much faster than one `dbus call` after another. \
+            Introspection is done once per object. The calls are sent on a connection of their \
+            own, so they don't come from the unique name of a --conn connection.\n\n\
+            Returns a row for each call, in the same order, with its status and either the \
+            result or the error. The rows are streamed as soon as they are available."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "batch", "pipeline", "many", "bulk", "call"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "dbus list org.mpris.MediaPlayer2.* | each { |dest| { \
+                dest: $dest, object: /org/mpris/MediaPlayer2, \
+                interface: org.freedesktop.DBus.Properties, method: Get, \
+                args: [org.mpris.MediaPlayer2.Player PlaybackStatus] } } | dbus batch",
+            description: "Get the playback status of every media player",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        let calls = input.into_iter().map(|row| parse_row(&row)).collect();
+        let replies = dbus.batch(calls, call.head)?;
+        Ok(PipelineData::list_stream(
+            ListStream::new(replies, call.head, engine.signals().clone()),
+            None,
+        ))
+    }
+}
+
+fn parse_row(row: &Value) -> Result<BatchCall, LabeledError> {
+    let record = row.as_record()?;
+    let column = |name: &str| record.get(name).filter(|value| !value.is_nothing());
+    let required = |name: &str| -> Result<Spanned<String>, LabeledError> {
+        let value = column(name).ok_or_else(|| {
+            LabeledError::new(format!("Missing column {name:?}")).with_label(
+                "every row needs dest, object, interface and method",
+                row.span(),
+            )
+        })?;
+        Ok(Spanned {
+            item: value.coerce_string()?,
+            span: value.span(),
+        })
+    };
+
+    Ok(BatchCall {
+        dest: required("dest")?,
+        object: required("object")?,
+        interface: required("interface")?,
+        method: required("method")?,
+        signature: column("signature")
+            .map(|value| {
+                Ok::<_, LabeledError>(Spanned {
+                    item: value.coerce_string()?,
+                    span: value.span(),
+                })
+            })
+            .transpose()?,
+        args: column("args")
+            .map(|value| value.as_list().map(|args| args.to_vec()))
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+#[test]
+fn test_batch() {
+    use std::time::Duration;
+
+    use crate::client::{next_call, ping_for, spanned, TestClient};
+
+    let test = TestClient::start(|_| ());
+
+    // A service that only replies once it has all three calls, in reverse order, and reports how
+    // often it was introspected
+    let (service_name, service) = test.serve(|service| {
+        let mut introspections = 0;
+        let mut calls = vec![];
+        while calls.len() < 3 {
+            let call = next_call(&service);
+            match call.member().as_deref() {
+                Some("Introspect") => {
+                    introspections += 1;
+                    let xml = r#"<node><interface name="com.example.Echo">
+                        <method name="Echo">
+                            <arg name="text" type="s" direction="in"/>
+                            <arg name="text" type="s" direction="out"/>
+                        </method>
+                    </interface></node>"#;
+                    service.send(call.method_return().append1(xml)).unwrap();
+                }
+                Some("Echo") => calls.push(call),
+                _ => (),
+            }
+        }
+        // Long enough for another call to be made while the batch is waiting
+        std::thread::sleep(Duration::from_millis(300));
+        for call in calls.into_iter().rev() {
+            let text: String = call.read1().unwrap();
+            service.send(call.method_return().append1(text)).unwrap();
+        }
+        service.flush();
+        introspections
+    });
+
+    let echo = |dest: &str, text: &str| {
+        Ok(BatchCall {
+            dest: spanned(dest),
+            object: spanned("/"),
+            interface: spanned("com.example.Echo"),
+            method: spanned("Echo"),
+            signature: None,
+            args: vec![Value::test_string(text)],
+        })
+    };
+    let calls = vec![
+        echo(&service_name, "one"),
+        Err(LabeledError::new("bad row")),
+        echo(&service_name, "two"),
+        echo("com.example.Missing", "nobody"),
+        echo(&service_name, "three"),
+    ];
+
+    let client = test.client();
+    let other = test.client_with(|config| config.introspect = false);
+    let (rows, ping) = std::thread::scope(|scope| {
+        // Calls made on the same connection while the batch is waiting still get their replies
+        let ping = scope.spawn(|| {
+            // Start once the batch is waiting
+            std::thread::sleep(Duration::from_millis(50));
+            ping_for(&other, Duration::from_millis(200))
+        });
+        let rows = client
+            .batch(calls, nu_protocol::Span::test_data())
+            .unwrap()
+            .map(|row| {
+                let row = row.into_record().unwrap();
+                match row.get("status").unwrap().as_str().unwrap() {
+                    "ok" => Ok(row.get("result").unwrap().as_str().unwrap().to_owned()),
+                    _ => Err(row.get("error").unwrap().as_str().unwrap().to_owned()),
+                }
+            })
+            .collect::<Vec<_>>();
+        (rows, ping.join().unwrap())
+    });
+    ping.unwrap();
+    assert_eq!(rows[0], Ok("one".into()));
+    assert_eq!(rows[1], Err("bad row".into()));
+    assert_eq!(rows[2], Ok("two".into()));
+    assert!(rows[3].is_err());
+    assert_eq!(rows[4], Ok("three".into()));
+    assert_eq!(service.join().unwrap(), 1);
+}
@@ -0,0 +1,193 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{
+    bench::BenchOptions, client::BatchCall, config::DbusClientConfig, DbusSignatureUtilExt,
+};
+
+pub struct Bench;
+
+impl SimplePluginCommand for Bench {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus bench"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .accepts_timeout()
+            .accepts_unsafe()
+            .accepts_no_auto_start()
+            .accepts_interactive_auth()
+            .input_output_type(Type::Nothing, Type::Record(vec![].into()))
+            .named(
+                "count",
+                SyntaxShape::Int,
+                "How many calls to make in total (default 1000, unless --duration is given)",
+                None,
+            )
+            .named(
+                "duration",
+                SyntaxShape::Duration,
+                "Keep making calls for this long",
+                None,
+            )
+            .named(
+                "concurrency",
+                SyntaxShape::Int,
+                "How many calls to have waiting for a reply at once (default 1)",
+                None,
+            )
+            .named(
+                "signature",
+                SyntaxShape::String,
+                "Signature of the arguments to send, in D-Bus format.\n    \
+                 If not provided, they will be determined from introspection.\n    \
+                 If --no-introspect is specified and this is not provided, they will \
+                   be guessed (poorly)",
+                None,
+            )
+            .switch(
+                "no-introspect",
+                "Don't use introspection to determine the correct argument signature",
+                None,
+            )
+            .named(
+                "dest",
+                SyntaxShape::String,
+                "The name of the connection to send the method to (default org.freedesktop.DBus)",
+                None,
+            )
+            .optional(
+                "object",
+                SyntaxShape::String,
+                "The path to the object to call the method on (default /)",
+            )
+            .optional(
+                "interface",
+                SyntaxShape::String,
+                "The name of the interface the method belongs to \
+                 (default org.freedesktop.DBus.Peer)",
+            )
+            .optional(
+                "method",
+                SyntaxShape::String,
+                "The name of the method to send (default Ping)",
+            )
+            .rest(
+                "args",
+                SyntaxShape::Any,
+                "Arguments to send with the method call",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Measure the latency and throughput of a method call"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Makes the same call over and over (Peer.Ping on the bus itself, by default) until the \
+            count or duration is reached, keeping up to --concurrency calls waiting for a reply \
+            at once on a connection of its own.\n\n\
+            Returns the number of calls made, how many succeeded and failed, the elapsed time, \
+            calls per second, latency statistics (min, mean, p50, p95, p99 and max) for the \
+            calls that succeeded, and how many calls failed with each error. Calls that get no \
+            reply within the timeout count as NoReply errors.\n\n\
+            With an audit log configured, only methods that read something can be benchmarked."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec![
+            "dbus",
+            "bench",
+            "benchmark",
+            "latency",
+            "throughput",
+            "performance",
+            "ping",
+        ]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "dbus bench --system --count 10000 --concurrency 16",
+                description: "Ping the system bus 10000 times, 16 calls at a time",
+                result: None,
+            },
+            Example {
+                example: "dbus bench --duration 10sec --dest org.freedesktop.login1 \
+                    /org/freedesktop/login1 org.freedesktop.DBus.Properties Get \
+                    org.freedesktop.login1.Manager IdleHint",
+                description: "Read a property from logind as often as possible for 10 seconds",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+
+        let positive = |flag: &str| -> Result<Option<usize>, LabeledError> {
+            let Some(value) = call.get_flag::<Spanned<i64>>(flag)? else {
+                return Ok(None);
+            };
+            match usize::try_from(value.item) {
+                Ok(n) if n > 0 => Ok(Some(n)),
+                _ => Err(LabeledError::new(format!("--{flag} must be positive"))
+                    .with_label("invalid value specified here", value.span)),
+            }
+        };
+        let duration = call
+            .get_flag::<Value>("duration")?
+            .map(|value| {
+                let nanos = value.as_duration()?;
+                u64::try_from(nanos)
+                    .ok()
+                    .filter(|nanos| *nanos > 0)
+                    .map(std::time::Duration::from_nanos)
+                    .ok_or_else(|| {
+                        LabeledError::new("--duration must be positive")
+                            .with_label("invalid duration specified here", value.span())
+                    })
+            })
+            .transpose()?;
+        let options = BenchOptions {
+            count: positive("count")?,
+            duration,
+            concurrency: positive("concurrency")?.unwrap_or(1),
+        };
+
+        let default = |index: usize, default: &str| -> Result<Spanned<String>, LabeledError> {
+            Ok(call.opt(index)?.unwrap_or_else(|| Spanned {
+                item: default.into(),
+                span: call.head,
+            }))
+        };
+        let bench_call = BatchCall {
+            dest: call.get_flag("dest")?.unwrap_or_else(|| Spanned {
+                item: "org.freedesktop.DBus".into(),
+                span: call.head,
+            }),
+            object: default(0, "/")?,
+            interface: default(1, "org.freedesktop.DBus.Peer")?,
+            method: default(2, "Ping")?,
+            signature: call.get_flag("signature")?,
+            args: call.positional.get(3..).unwrap_or_default().to_vec(),
+        };
+
+        Ok(dbus
+            .bench(&bench_call, &options, engine.signals())?
+            .to_value(call.head))
+    }
+}
@@ -1,11 +1,14 @@
-use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
-use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    record, Example, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value,
+};
 
-use crate::{client::DbusClient, config::DbusClientConfig, DbusSignatureUtilExt};
+use super::input::Params;
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
 
 pub struct Call;
 
-impl SimplePluginCommand for Call {
+impl PluginCommand for Call {
     type Plugin = crate::NuPluginDbus;
 
     fn name(&self) -> &str {
@@ -17,7 +20,11 @@ impl SimplePluginCommand for Call {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
-            .input_output_type(Type::Nothing, Type::Any)
+            .accepts_unsafe()
+            .accepts_retry()
+            .accepts_no_auto_start()
+            .accepts_interactive_auth()
+            .input_output_types(vec![(Type::Nothing, Type::Any), (Type::Any, Type::Any)])
             .named(
                 "signature",
                 SyntaxShape::String,
@@ -32,28 +39,42 @@ impl SimplePluginCommand for Call {
                 "Always return a list of all return values",
                 None,
             )
+            .switch(
+                "async",
+                "Return a pending call right away instead of waiting for the result, \
+                 which `dbus await` can collect later",
+                None,
+            )
+            .switch(
+                "no-reply",
+                "Don't wait for a reply, and return nothing as soon as the call has been sent. \
+                 This is automatic for methods annotated with \
+                 org.freedesktop.DBus.Method.NoReply",
+                None,
+            )
             .switch(
                 "no-introspect",
                 "Don't use introspection to determine the correct argument signature",
                 None,
             )
-            .required_named(
+            .accepts_all_matching()
+            .named(
                 "dest",
                 SyntaxShape::String,
                 "The name of the connection to send the method to",
                 None,
             )
-            .required(
+            .optional(
                 "object",
                 SyntaxShape::String,
                 "The path to the object to call the method on",
             )
-            .required(
+            .optional(
                 "interface",
                 SyntaxShape::String,
                 "The name of the interface the method belongs to",
             )
-            .required(
+            .optional(
                 "method",
                 SyntaxShape::String,
                 "The name of the method to send",
@@ -70,7 +91,12 @@ impl SimplePluginCommand for Call {
     }
 
     fn extra_description(&self) -> &str {
-        "Returns an array if the method call returns more than one value."
+        "Returns an array if the method call returns more than one value.\n\n\
+            Given a list of records as input, the method is called once for each of them. \
+            Their columns (dest, object, interface, method, signature and args) take the place \
+            of the arguments, which are used for anything the records leave out. \
+            A string is taken as the dest, and a list as the args, of each call. Anything else \
+            is refused. The results are streamed."
     }
 
     fn search_terms(&self) -> Vec<&str> {
@@ -93,35 +119,155 @@ impl SimplePluginCommand for Call {
                 description: "Show a notification on the desktop for 5 seconds",
                 result: None,
             },
+            Example {
+                example: "dbus call --no-reply --dest=org.mpris.MediaPlayer2.spotify \
+                    /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player PlayPause",
+                description: "Toggle playback without waiting for the player to respond",
+                result: None,
+            },
+            Example {
+                example: "let calls = [org.freedesktop.PackageKit org.freedesktop.fwupd] | \
+                    each { |dest| dbus call --system --async --dest=$dest \
+                    / org.freedesktop.DBus.Peer GetMachineId }; $calls | dbus await",
+                description: "Make calls in parallel and collect the results afterwards",
+                result: None,
+            },
+            Example {
+                example: "dbus list org.mpris.MediaPlayer2.* | \
+                    dbus call /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player Stop",
+                description: "Stop every media player, one after another",
+                result: None,
+            },
+            Example {
+                example: "dbus call --all-matching org.mpris.MediaPlayer2.* \
+                    /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player Pause",
+                description: "Pause every media player",
+                result: None,
+            },
         ]
     }
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
-        _input: &Value,
-    ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
-        let values = dbus.call(
-            &call.get_flag("dest")?.unwrap(),
-            &call.req(0)?,
-            &call.req(1)?,
-            &call.req(2)?,
-            call.get_flag("signature")?.as_ref(),
-            &call.positional[3..],
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let time_limit = config.time_limit();
+        let dbus = plugin.client(engine, config)?;
+        let all_matching = super::all_matching_unless_dest(call)?;
+        let mut params = Params::from_call(
+            call,
+            &input,
+            &["object", "interface", "method"],
+            &["dest", "signature"],
         )?;
-
+        let args = call.positional.get(3..).unwrap_or_default().to_vec();
+        params.insert("args", Value::list(args, call.head));
         let flatten = !call.get_flag::<bool>("no-flatten")?.unwrap_or(false);
+        let head = call.head;
+        let background = call.has_flag("async")?.then(|| dbus.background_call());
+        // How many calls will be made, if that's known ahead of time
+        let calls = match &input {
+            _ if all_matching.is_some() => None,
+            PipelineData::Value(Value::List { vals, .. }, _) => Some(vals.len()),
+            PipelineData::ListStream(..) | PipelineData::ByteStream(..) => None,
+            _ => Some(1),
+        };
+        let time_limit = calls.map(|calls| time_limit.saturating_mul(calls as u32));
+
+        // Everything the call needs is owned, so that it can also run in the background
+        let description = (
+            params
+                .opt_string("dest")?
+                .or(all_matching.clone())
+                .map(|dest| dest.item)
+                .unwrap_or_default(),
+            match (
+                params.opt_string("interface")?,
+                params.opt_string("method")?,
+            ) {
+                (Some(interface), Some(method)) => format!("{}.{}", interface.item, method.item),
+                _ => String::new(),
+            },
+        );
+        // Each item of the input is a record of parameters, a destination, or a list of arguments
+        let call_item = move |item: Option<Value>| {
+            let params = params.with_item(item.map(item_params).transpose()?, "args")?;
+            let object = params.string("object")?;
+            let interface = params.string("interface")?;
+            let method = params.string("method")?;
+            let signature = params.opt_string("signature")?;
+            let args = params.value("args")?.as_list()?;
+
+            let call_dest = |dest: &Spanned<String>| {
+                let values =
+                    dbus.call(dest, &object, &interface, &method, signature.as_ref(), args)?;
+
+                // Make the output easier to deal with by returning a list only if there are
+                // multiple return values (not so common)
+                match values.len() {
+                    0 if flatten => Ok(Value::nothing(head)),
+                    1 if flatten => Ok(values.into_iter().nth(0).unwrap()),
+                    _ => Ok(Value::list(values, head)),
+                }
+            };
 
-        // Make the output easier to deal with by returning a list only if there are multiple return
-        // values (not so common)
-        match values.len() {
-            0 if flatten => Ok(Value::nothing(call.head)),
-            1 if flatten => Ok(values.into_iter().nth(0).unwrap()),
-            _ => Ok(Value::list(values, call.head)),
+            match (params.opt_string("dest")?, &all_matching) {
+                (Some(dest), _) => call_dest(&dest),
+                (None, Some(pattern)) => dbus.for_each_matching(pattern, head, call_dest),
+                (None, None) => Err(super::missing_dest(head)),
+            }
+        };
+
+        if let Some(background) = background {
+            let (dest, method) = description;
+            let background_engine = engine.clone();
+            plugin
+                .add_pending_call(engine, dest, method, time_limit, move || {
+                    // The results for every item are collected, as nothing streams from here
+                    let result =
+                        super::input::for_each_item(&background_engine, head, input, call_item)
+                            .and_then(|data| Ok(data.into_value(head)?));
+                    drop(background);
+                    result
+                })
+                .map(|handle| PipelineData::value(handle.into_value(head), None))
+        } else {
+            super::input::for_each_item(engine, head, input, call_item)
         }
     }
 }
+
+/// The parameters given by an item of the input: a record of them, a string as the destination, or
+/// a list as the arguments
+fn item_params(item: Value) -> Result<Value, LabeledError> {
+    match item {
+        Value::String { .. } => {
+            let span = item.span();
+            Ok(Value::record(record!("dest" => item), span))
+        }
+        Value::Record { .. } | Value::List { .. } => Ok(item),
+        _ => Err(LabeledError::new("Unsupported input").with_label(
+            "expected a record of parameters, a destination, or a list of arguments",
+            item.span(),
+        )),
+    }
+}
+
+#[test]
+fn test_item_params() {
+    assert_eq!(
+        item_params(Value::test_string("org.mpris.MediaPlayer2.vlc")).unwrap(),
+        Value::test_record(record!(
+            "dest" => Value::test_string("org.mpris.MediaPlayer2.vlc")
+        ))
+    );
+    let args = Value::test_list(vec![Value::test_int(1)]);
+    assert_eq!(item_params(args.clone()).unwrap(), args);
+    let record = Value::test_record(record!("method" => Value::test_string("Stop")));
+    assert_eq!(item_params(record.clone()).unwrap(), record);
+    assert!(item_params(Value::test_int(1)).is_err());
+}
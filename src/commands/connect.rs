@@ -0,0 +1,60 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Type, Value};
+
+use crate::{config::DbusClientConfig, connection::DbusConnection, DbusSignatureUtilExt};
+
+pub struct Connect;
+
+impl SimplePluginCommand for Connect {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus connect"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .input_output_type(Type::Nothing, Type::Custom("dbus connection".into()))
+    }
+
+    fn description(&self) -> &str {
+        "Open a dedicated connection to D-Bus"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Returns a handle that can be passed to --conn on the other commands, so that they all \
+            use the same connection and unique name. This matters for services that track their \
+            clients by unique name. The connection is closed when the handle is no longer \
+            referenced."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "open", "connection", "unique", "name"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "let conn = dbus connect; \
+                dbus call --conn $conn --dest=org.freedesktop.DBus \
+                /org/freedesktop/DBus org.freedesktop.DBus GetConnectionUnixProcessID \
+                $conn.unique_name",
+            description: "Look up our own process ID through the bus, using the same connection",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let conn = DbusConnection::open(&config.bus_choice, config.auth.as_ref())?;
+        let handle = plugin.add_connection(engine, conn)?;
+        Ok(handle.into_value(call.head))
+    }
+}
@@ -0,0 +1,72 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Type, Value};
+
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
+
+pub struct Disconnect;
+
+impl SimplePluginCommand for Disconnect {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus disconnect"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .input_output_type(Type::Nothing, Type::Nothing)
+    }
+
+    fn description(&self) -> &str {
+        "Close connections kept open between commands"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Connections to each bus are reused by later commands. \
+            If a bus is specified, only the connection to that bus is closed, \
+            otherwise all of them are. They will be reopened when needed."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "close", "connection", "pool", "reset"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "dbus disconnect",
+                description: "Close all connections",
+                result: None,
+            },
+            Example {
+                example: "dbus disconnect --system",
+                description: "Close the connection to the system bus",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let bus_specified = call.named.iter().any(|(name, _)| {
+            matches!(
+                &name.item[..],
+                "session" | "system" | "started" | "user" | "bus" | "peer"
+            )
+        });
+        if bus_specified {
+            let config = DbusClientConfig::new(engine, call)?;
+            plugin.disconnect(Some(&config.bus_choice.item));
+        } else {
+            plugin.disconnect(None);
+        }
+        Ok(Value::nothing(call.head))
+    }
+}
@@ -0,0 +1,113 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Spanned, Type, Value};
+
+use crate::{
+    config::{DbusBusChoice, DbusClientConfig},
+    doctor::diagnose,
+    DbusSignatureUtilExt,
+};
+
+pub struct Doctor;
+
+impl SimplePluginCommand for Doctor {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus doctor"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .accepts_timeout()
+            .input_output_type(Type::Nothing, Type::Table(vec![].into()))
+    }
+
+    fn description(&self) -> &str {
+        "Diagnose problems connecting to D-Bus"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Checks the environment, the address, the socket, authentication, registering on the \
+            bus, whether the bus responds, and which optional features it has. \
+            Without a bus specified, by a flag or in the plugin config, both the session and \
+            system buses are checked. \
+            Each row has a status of pass, warn, fail or skip, and a hint for fixing problems."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec![
+            "dbus",
+            "diagnose",
+            "debug",
+            "troubleshoot",
+            "check",
+            "health",
+        ]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "dbus doctor",
+                description: "Check the session and system buses",
+                result: None,
+            },
+            Example {
+                example: "dbus doctor --system | where status == fail",
+                description: "Show what's wrong with the connection to the system bus",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        _plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        if let DbusBusChoice::Connection(_) = config.bus_choice.item {
+            return Err(
+                LabeledError::new("Can't diagnose an open connection").with_label(
+                    "check the bus it was opened to instead",
+                    config.bus_choice.span,
+                ),
+            );
+        }
+        let bus_specified = config.bus_chosen;
+        let buses = if bus_specified {
+            vec![config.bus_choice.clone()]
+        } else {
+            [DbusBusChoice::Session, DbusBusChoice::System]
+                .map(|item| Spanned {
+                    item,
+                    span: call.head,
+                })
+                .into()
+        };
+
+        let mut rows = vec![];
+        for bus_choice in &buses {
+            let bus = match &bus_choice.item {
+                DbusBusChoice::Session => "session".into(),
+                DbusBusChoice::System => "system".into(),
+                DbusBusChoice::Started => "started".into(),
+                DbusBusChoice::User(uid) => format!("user {uid}"),
+                DbusBusChoice::Bus(address) | DbusBusChoice::Peer(address) => address.clone(),
+                DbusBusChoice::Connection(_) => "connection".into(),
+            };
+            // --auth only applies to a chosen bus
+            let auth = config.auth.as_ref().filter(|_| bus_specified);
+            rows.extend(
+                diagnose(bus_choice, auth, config.timeout.item)
+                    .iter()
+                    .map(|check| check.to_value(&bus, call.head)),
+            );
+        }
+        Ok(Value::list(rows, call.head))
+    }
+}
@@ -0,0 +1,67 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
+
+use crate::DbusSignatureUtilExt;
+
+pub struct FdClose;
+
+impl SimplePluginCommand for FdClose {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus fd close"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_type(Type::Nothing, Type::Nothing)
+            .required(
+                "fd",
+                SyntaxShape::Any,
+                "A file descriptor received in a D-Bus reply",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Close a file descriptor received in a D-Bus reply"
+    }
+
+    fn extra_description(&self) -> &str {
+        "File descriptors are closed anyway once nothing refers to them any more, but some \
+            services wait for the other end to be closed, e.g. to finish a transfer."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec![
+            "dbus",
+            "fd",
+            "file",
+            "descriptor",
+            "unix",
+            "close",
+            "release",
+        ]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "let lock = dbus call --system --dest=org.freedesktop.login1 \
+                /org/freedesktop/login1 org.freedesktop.login1.Manager Inhibit \
+                sleep nu \"Backing up\" block; backup; dbus fd close $lock",
+            description: "Keep the system from sleeping until a backup is done",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        plugin.close_fd(engine, &call.req(0)?)?;
+        Ok(Value::nothing(call.head))
+    }
+}
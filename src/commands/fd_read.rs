@@ -0,0 +1,76 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    ByteStream, ByteStreamType, Example, LabeledError, PipelineData, Signature, SyntaxShape, Type,
+    Value,
+};
+
+use crate::DbusSignatureUtilExt;
+
+pub struct FdRead;
+
+impl PluginCommand for FdRead {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus fd read"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_type(Type::Nothing, Type::Binary)
+            .required(
+                "fd",
+                SyntaxShape::Any,
+                "A file descriptor received in a D-Bus reply",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Read from a file descriptor received in a D-Bus reply"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Streams everything that can be read from the file descriptor until the end of the file. \
+            The file descriptor stays open afterward, until it is closed with `dbus fd close` or \
+            no longer used."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "fd", "file", "descriptor", "unix", "read", "stream"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "let dump = dbus call --system --dest=org.freedesktop.systemd1 \
+                /org/freedesktop/systemd1 org.freedesktop.systemd1.Manager \
+                DumpByFileDescriptor; dbus fd read $dump | decode",
+            description: "Read the state dump of systemd from the file descriptor it returns",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let fd: Value = call.req(0)?;
+        // Read from a copy, so that closing the original doesn't cut the stream short
+        let file = plugin.fds().get(&fd)?.try_clone().map_err(|err| {
+            LabeledError::new("Failed to read from the file descriptor")
+                .with_label(err.to_string(), fd.span())
+        })?;
+        Ok(PipelineData::byte_stream(
+            ByteStream::read(
+                file,
+                call.head,
+                engine.signals().clone(),
+                ByteStreamType::Unknown,
+            ),
+            None,
+        ))
+    }
+}
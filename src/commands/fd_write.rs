@@ -0,0 +1,65 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{Example, LabeledError, PipelineData, Signature, SyntaxShape, Type, Value};
+
+use crate::DbusSignatureUtilExt;
+
+pub struct FdWrite;
+
+impl PluginCommand for FdWrite {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus fd write"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_types(vec![
+                (Type::String, Type::Nothing),
+                (Type::Binary, Type::Nothing),
+            ])
+            .required(
+                "fd",
+                SyntaxShape::Any,
+                "A file descriptor received in a D-Bus reply",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Write the input to a file descriptor received in a D-Bus reply"
+    }
+
+    fn extra_description(&self) -> &str {
+        "The file descriptor stays open afterward, so that more can be written to it. Close it \
+            with `dbus fd close` to let the other end see the end of the file."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "fd", "file", "descriptor", "unix", "write", "save"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "open --raw data.bin | dbus fd write $fd; dbus fd close $fd",
+            description: "Write a file to a file descriptor, then close it",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        _engine: &EngineInterface,
+        call: &EvaluatedCall,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let fd: Value = call.req(0)?;
+        let file = plugin.fds().get(&fd)?;
+        input.write_to(&*file).map_err(|err| {
+            LabeledError::new("Failed to write to the file descriptor")
+                .with_label(err.to_string(), fd.span())
+        })?;
+        Ok(PipelineData::Empty)
+    }
+}
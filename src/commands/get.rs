@@ -1,11 +1,14 @@
-use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
-use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    Example, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value,
+};
 
-use crate::{client::DbusClient, config::DbusClientConfig, DbusSignatureUtilExt};
+use super::input::Params;
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
 
 pub struct Get;
 
-impl SimplePluginCommand for Get {
+impl PluginCommand for Get {
     type Plugin = crate::NuPluginDbus;
 
     fn name(&self) -> &str {
@@ -17,24 +20,27 @@ impl SimplePluginCommand for Get {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
-            .input_output_type(Type::Nothing, Type::Any)
-            .required_named(
+            .accepts_retry()
+            .accepts_no_auto_start()
+            .input_output_types(vec![(Type::Nothing, Type::Any), (Type::Any, Type::Any)])
+            .accepts_all_matching()
+            .named(
                 "dest",
                 SyntaxShape::String,
                 "The name of the connection to read the property from",
                 None,
             )
-            .required(
+            .optional(
                 "object",
                 SyntaxShape::String,
                 "The path to the object to read the property from",
             )
-            .required(
+            .optional(
                 "interface",
                 SyntaxShape::String,
                 "The name of the interface the property belongs to",
             )
-            .required(
+            .optional(
                 "property",
                 SyntaxShape::String,
                 "The name of the property to read",
@@ -45,6 +51,12 @@ impl SimplePluginCommand for Get {
         "Get a D-Bus property"
     }
 
+    fn extra_description(&self) -> &str {
+        "Given a list as input, the property is read once for each item, and the values are \
+            streamed. Records take the place of the arguments by their columns (dest, object, \
+            interface and property), and anything else is taken as the property name."
+    }
+
     fn search_terms(&self) -> Vec<&str> {
         vec!["dbus", "property", "read"]
     }
@@ -68,18 +80,37 @@ impl SimplePluginCommand for Get {
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
-        _input: &Value,
-    ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
-        dbus.get(
-            &call.get_flag("dest")?.unwrap(),
-            &call.req(0)?,
-            &call.req(1)?,
-            &call.req(2)?,
-        )
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        let all_matching = super::all_matching_unless_dest(call)?;
+        let params = Params::from_call(
+            call,
+            &input,
+            &["object", "interface", "property"],
+            &["dest"],
+        )?;
+        let head = call.head;
+
+        // Each item of the input is either a record of parameters or a property name
+        let get_item = move |item: Option<Value>| {
+            let params = params.with_item(item, "property")?;
+            let object = params.string("object")?;
+            let interface = params.string("interface")?;
+            let property = params.string("property")?;
+            let get_dest = |dest: &Spanned<String>| dbus.get(dest, &object, &interface, &property);
+
+            match (params.opt_string("dest")?, &all_matching) {
+                (Some(dest), _) => get_dest(&dest),
+                (None, Some(pattern)) => dbus.for_each_matching(pattern, head, get_dest),
+                (None, None) => Err(super::missing_dest(head)),
+            }
+        };
+
+        super::input::for_each_item(engine, head, input, get_item)
     }
 }
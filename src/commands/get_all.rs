@@ -1,7 +1,7 @@
 use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
-use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
+use nu_protocol::{Example, LabeledError, Signature, Spanned, SyntaxShape, Type, Value};
 
-use crate::{client::DbusClient, config::DbusClientConfig, DbusSignatureUtilExt};
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
 
 pub struct GetAll;
 
@@ -17,8 +17,14 @@ impl SimplePluginCommand for GetAll {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
-            .input_output_type(Type::Nothing, Type::Record(vec![].into()))
-            .required_named(
+            .accepts_retry()
+            .accepts_no_auto_start()
+            .input_output_types(vec![
+                (Type::Nothing, Type::Record(vec![].into())),
+                (Type::Nothing, Type::Table(vec![].into())),
+            ])
+            .accepts_all_matching()
+            .named(
                 "dest",
                 SyntaxShape::String,
                 "The name of the connection to read the property from",
@@ -60,17 +66,23 @@ impl SimplePluginCommand for GetAll {
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
         _input: &Value,
     ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
-        dbus.get_all(
-            &call.get_flag("dest")?.unwrap(),
-            &call.req(0)?,
-            &call.req(1)?,
-        )
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        let (object, interface) = (call.req(0)?, call.req(1)?);
+        let get_all_dest = |dest: &Spanned<String>| dbus.get_all(dest, &object, &interface);
+
+        match super::dest_unless_all_matching(call)? {
+            Some(dest) => get_all_dest(&dest),
+            None => dbus.for_each_matching(
+                &call.get_flag("all-matching")?.unwrap(),
+                call.head,
+                get_all_dest,
+            ),
+        }
     }
 }
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use nu_plugin::{EngineInterface, EvaluatedCall};
+use nu_protocol::{
+    LabeledError, ListStream, PipelineData, Record, ShellError, Span, Spanned, Value,
+};
+
+/// The parameters of a command, given as arguments and optionally overridden by each item of the
+/// pipeline input
+#[derive(Debug, Clone)]
+pub struct Params {
+    names: Vec<&'static str>,
+    /// The positional parameters, which have to be given one way or the other
+    required: Vec<&'static str>,
+    values: HashMap<&'static str, Value>,
+    head: Span,
+}
+
+impl Params {
+    /// Take the parameters from the positional arguments of the call (in order) and the named
+    /// flags. Either can be overridden by a column of the same name in the input.
+    ///
+    /// The positional parameters are required, so without any input they all have to be given
+    /// as arguments.
+    pub fn from_call(
+        call: &EvaluatedCall,
+        input: &PipelineData,
+        positional: &[&'static str],
+        named: &[&'static str],
+    ) -> Result<Params, LabeledError> {
+        let mut values = HashMap::new();
+        for (name, value) in positional.iter().zip(&call.positional) {
+            values.insert(*name, value.clone());
+        }
+        for name in named {
+            if let Some(value) = call.get_flag::<Value>(name)? {
+                values.insert(*name, value);
+            }
+        }
+        let params = Params {
+            names: positional.iter().chain(named).copied().collect(),
+            required: positional.to_vec(),
+            values,
+            head: call.head,
+        };
+        if matches!(
+            input,
+            PipelineData::Empty | PipelineData::Value(Value::Nothing { .. }, _)
+        ) {
+            params.check_required()?;
+        }
+        Ok(params)
+    }
+
+    /// Set a parameter that isn't given by a single argument
+    pub fn insert(&mut self, name: &'static str, value: Value) {
+        if !self.names.contains(&name) {
+            self.names.push(name);
+        }
+        self.values.insert(name, value);
+    }
+
+    /// Apply an item of the input, if there is one: a record overrides parameters by column name,
+    /// and anything else is used as the `otherwise` parameter
+    ///
+    /// Fails if a required parameter is still missing afterwards.
+    pub fn with_item(
+        &self,
+        item: Option<Value>,
+        otherwise: &'static str,
+    ) -> Result<Params, LabeledError> {
+        let mut params = self.clone();
+        match item {
+            Some(Value::Record { val, .. }) => params.override_with(&val)?,
+            Some(item) => params.insert(otherwise, item),
+            None => (),
+        }
+        params.check_required()?;
+        Ok(params)
+    }
+
+    fn check_required(&self) -> Result<(), LabeledError> {
+        let missing = self
+            .required
+            .iter()
+            .filter(|name| !self.values.contains_key(*name))
+            .copied()
+            .collect::<Vec<_>>();
+        match missing[..] {
+            [] => Ok(()),
+            [name] => Err(self.missing(name)),
+            _ => Err(
+                LabeledError::new(format!("Missing {}", missing.join(", "))).with_label(
+                    "give these as arguments, or as columns of the input",
+                    self.head,
+                ),
+            ),
+        }
+    }
+
+    fn missing(&self, name: &str) -> LabeledError {
+        LabeledError::new(format!("Missing {name}")).with_label(
+            format!("give {name} as an argument, or as a column of the input"),
+            self.head,
+        )
+    }
+
+    fn override_with(&mut self, record: &Record) -> Result<(), LabeledError> {
+        for (column, value) in record {
+            let name = self
+                .names
+                .iter()
+                .find(|name| **name == column)
+                .ok_or_else(|| {
+                    LabeledError::new(format!("Unknown column {column:?} in the input")).with_label(
+                        format!("expected columns among: {}", self.names.join(", ")),
+                        value.span(),
+                    )
+                })?;
+            if !value.is_nothing() {
+                self.values.insert(name, value.clone());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    /// Get a required parameter
+    pub fn value(&self, name: &str) -> Result<&Value, LabeledError> {
+        self.get(name).ok_or_else(|| self.missing(name))
+    }
+
+    /// Get a required string parameter
+    pub fn string(&self, name: &str) -> Result<Spanned<String>, LabeledError> {
+        let value = self.value(name)?;
+        Ok(Spanned {
+            item: value.coerce_string()?,
+            span: value.span(),
+        })
+    }
+
+    /// Get an optional string parameter
+    pub fn opt_string(&self, name: &str) -> Result<Option<Spanned<String>>, LabeledError> {
+        self.get(name).map(|_| self.string(name)).transpose()
+    }
+}
+
+/// Run an operation once if there is no input, or once per item of a list or stream, streaming
+/// the results
+///
+/// Errors for individual items are passed along in the stream.
+pub fn for_each_item(
+    engine: &EngineInterface,
+    head: Span,
+    input: PipelineData,
+    op: impl Fn(Option<Value>) -> Result<Value, LabeledError> + Send + 'static,
+) -> Result<PipelineData, LabeledError> {
+    match input {
+        PipelineData::Empty | PipelineData::Value(Value::Nothing { .. }, _) => {
+            Ok(PipelineData::value(op(None)?, None))
+        }
+        PipelineData::Value(Value::List { .. }, _) | PipelineData::ListStream(..) => {
+            let results = input.into_iter().map(move |item| {
+                op(Some(item))
+                    .unwrap_or_else(|err| Value::error(ShellError::LabeledError(err.into()), head))
+            });
+            Ok(PipelineData::list_stream(
+                ListStream::new(results, head, engine.signals().clone()),
+                None,
+            ))
+        }
+        PipelineData::Value(item, _) => Ok(PipelineData::value(op(Some(item))?, None)),
+        PipelineData::ByteStream(stream, _) => Err(LabeledError::new("Unsupported input")
+            .with_label("expected a record, a list, or nothing", stream.span())),
+    }
+}
+
+#[test]
+fn test_params_with_item() {
+    use nu_protocol::record;
+
+    let params = Params {
+        names: vec!["object", "property", "dest"],
+        required: vec!["object", "property"],
+        values: HashMap::from([
+            ("object", Value::test_string("/org/test")),
+            ("property", Value::test_string("Name")),
+        ]),
+        head: Span::test_data(),
+    };
+
+    // A record overrides by column, leaving the rest alone
+    let item = Value::test_record(record!(
+        "property" => Value::test_string("Version"),
+        "dest" => Value::test_string("org.test"),
+        "object" => Value::test_nothing(),
+    ));
+    let applied = params.with_item(Some(item), "property").unwrap();
+    assert_eq!(applied.string("object").unwrap().item, "/org/test");
+    assert_eq!(applied.string("property").unwrap().item, "Version");
+    assert_eq!(
+        applied.opt_string("dest").unwrap().unwrap().item,
+        "org.test"
+    );
+
+    // Anything else is the `otherwise` parameter
+    let applied = params
+        .with_item(Some(Value::test_string("Version")), "property")
+        .unwrap();
+    assert_eq!(applied.string("property").unwrap().item, "Version");
+    assert!(applied.opt_string("dest").unwrap().is_none());
+
+    let unknown = Value::test_record(record!("method" => Value::test_string("Get")));
+    assert!(params.with_item(Some(unknown), "property").is_err());
+
+    // Required parameters have to be there after the item is applied
+    let mut missing = params.clone();
+    missing.values.remove("property");
+    assert!(missing.with_item(None, "property").is_err());
+    let err = missing
+        .with_item(
+            Some(Value::test_record(
+                record!("dest" => Value::test_string("org.test")),
+            )),
+            "property",
+        )
+        .unwrap_err();
+    assert_eq!(err.msg, "Missing property");
+    assert_eq!(err.labels[0].span, Span::test_data());
+    missing.values.remove("object");
+    assert_eq!(
+        missing.with_item(None, "property").unwrap_err().msg,
+        "Missing object, property"
+    );
+}
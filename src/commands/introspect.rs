@@ -1,7 +1,7 @@
 use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
 use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
 
-use crate::{client::DbusClient, config::DbusClientConfig, DbusSignatureUtilExt};
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
 
 pub struct Introspect;
 
@@ -17,6 +17,8 @@ impl SimplePluginCommand for Introspect {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
+            .accepts_retry()
+            .accepts_no_auto_start()
             .input_output_type(Type::Nothing, Type::Record(vec![].into()))
             .required_named(
                 "dest",
@@ -71,13 +73,13 @@ impl SimplePluginCommand for Introspect {
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
         _input: &Value,
     ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
         let node = dbus.introspect(&call.get_flag("dest")?.unwrap(), &call.req(0)?)?;
         Ok(node.to_value(call.head))
     }
@@ -1,7 +1,7 @@
 use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
 use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
 
-use crate::{client::DbusClient, config::DbusClientConfig, pattern::Pattern, DbusSignatureUtilExt};
+use crate::{config::DbusClientConfig, pattern::Pattern, DbusSignatureUtilExt};
 
 pub struct List;
 
@@ -17,6 +17,12 @@ impl SimplePluginCommand for List {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
+            .switch(
+                "activatable",
+                "List the names that the bus can start services for, instead of the names \
+                 currently on the bus",
+                None,
+            )
             .input_output_type(Type::Nothing, Type::List(Type::String.into()))
             .optional(
                 "pattern",
@@ -55,6 +61,11 @@ impl SimplePluginCommand for List {
                     Value::test_string("org.freedesktop.Notifications"),
                 ])),
             },
+            Example {
+                example: "dbus list --activatable org.freedesktop.*",
+                description: "List freedesktop.org services that can be started on demand",
+                result: None,
+            },
             Example {
                 example: "dbus list org.mpris.MediaPlayer2.**",
                 description: "List all MPRIS2 media players on the bus",
@@ -68,17 +79,21 @@ impl SimplePluginCommand for List {
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
         _input: &Value,
     ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
         let pattern = call
             .opt::<String>(0)?
             .map(|pat| Pattern::new(&pat, Some('.')));
-        let result = dbus.list(pattern.as_ref())?;
+        let result = if call.has_flag("activatable")? {
+            dbus.list_activatable(pattern.as_ref())?
+        } else {
+            dbus.list(pattern.as_ref())?
+        };
         Ok(Value::list(
             result
                 .into_iter()
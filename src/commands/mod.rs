@@ -1,15 +1,77 @@
+mod address_parse;
+mod await_call;
+mod batch;
+mod bench;
 mod call;
+mod connect;
+mod disconnect;
+mod doctor;
+mod fd_close;
+mod fd_read;
+mod fd_write;
 mod get;
 mod get_all;
+mod input;
 mod introspect;
 mod list;
 mod main;
 mod set;
+mod start_service;
+mod test_bus;
+mod whoami;
 
+pub use address_parse::AddressParse;
+pub use await_call::Await;
+pub use batch::Batch;
+pub use bench::Bench;
 pub use call::Call;
+pub use connect::Connect;
+pub use disconnect::Disconnect;
+pub use doctor::Doctor;
+pub use fd_close::FdClose;
+pub use fd_read::FdRead;
+pub use fd_write::FdWrite;
 pub use get::Get;
 pub use get_all::GetAll;
 pub use introspect::Introspect;
 pub use list::List;
 pub use main::Main;
 pub use set::Set;
+pub use start_service::StartService;
+pub use test_bus::TestBus;
+pub use whoami::Whoami;
+
+/// Get the destination for commands that accept either `--dest` or `--all-matching`
+///
+/// Returns `Ok(None)` if `--all-matching` was specified instead.
+fn dest_unless_all_matching(
+    call: &nu_plugin::EvaluatedCall,
+) -> Result<Option<nu_protocol::Spanned<String>>, nu_protocol::LabeledError> {
+    let all_matching = all_matching_unless_dest(call)?;
+    let dest: Option<nu_protocol::Spanned<String>> = call.get_flag("dest")?;
+    if dest.is_none() && all_matching.is_none() {
+        return Err(missing_dest(call.head));
+    }
+    Ok(dest)
+}
+
+/// Get the `--all-matching` pattern, making sure that `--dest` isn't also specified
+fn all_matching_unless_dest(
+    call: &nu_plugin::EvaluatedCall,
+) -> Result<Option<nu_protocol::Spanned<String>>, nu_protocol::LabeledError> {
+    use nu_protocol::{LabeledError, Spanned};
+
+    let dest: Option<Spanned<String>> = call.get_flag("dest")?;
+    match (dest, call.get_flag("all-matching")?) {
+        (Some(dest), Some(_)) => Err(LabeledError::new(
+            "--dest and --all-matching can't be used together",
+        )
+        .with_label("remove this or --all-matching", dest.span)),
+        (_, all_matching) => Ok(all_matching),
+    }
+}
+
+fn missing_dest(head: nu_protocol::Span) -> nu_protocol::LabeledError {
+    nu_protocol::LabeledError::new("Missing destination")
+        .with_label("either --dest or --all-matching is required", head)
+}
@@ -1,11 +1,14 @@
-use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
-use nu_protocol::{Example, LabeledError, Signature, SyntaxShape, Type, Value};
+use nu_plugin::{EngineInterface, EvaluatedCall, PluginCommand};
+use nu_protocol::{
+    Example, LabeledError, PipelineData, Signature, Spanned, SyntaxShape, Type, Value,
+};
 
-use crate::{client::DbusClient, config::DbusClientConfig, DbusSignatureUtilExt};
+use super::input::Params;
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
 
 pub struct Set;
 
-impl SimplePluginCommand for Set {
+impl PluginCommand for Set {
     type Plugin = crate::NuPluginDbus;
 
     fn name(&self) -> &str {
@@ -17,7 +20,11 @@ impl SimplePluginCommand for Set {
             .dbus_command()
             .accepts_dbus_client_options()
             .accepts_timeout()
-            .input_output_type(Type::Nothing, Type::Nothing)
+            .accepts_unsafe()
+            .accepts_retry()
+            .accepts_no_auto_start()
+            .accepts_interactive_auth()
+            .input_output_types(vec![(Type::Nothing, Type::Any), (Type::Any, Type::Any)])
             .named(
                 "signature",
                 SyntaxShape::String,
@@ -27,28 +34,29 @@ impl SimplePluginCommand for Set {
                        be guessed (poorly)",
                 None,
             )
-            .required_named(
+            .accepts_all_matching()
+            .named(
                 "dest",
                 SyntaxShape::String,
                 "The name of the connection to write the property on",
                 None,
             )
-            .required(
+            .optional(
                 "object",
                 SyntaxShape::String,
                 "The path to the object to write the property on",
             )
-            .required(
+            .optional(
                 "interface",
                 SyntaxShape::String,
                 "The name of the interface the property belongs to",
             )
-            .required(
+            .optional(
                 "property",
                 SyntaxShape::String,
                 "The name of the property to write",
             )
-            .required(
+            .optional(
                 "value",
                 SyntaxShape::Any,
                 "The value to write to the property",
@@ -59,37 +67,96 @@ impl SimplePluginCommand for Set {
         "Set a D-Bus property"
     }
 
+    fn extra_description(&self) -> &str {
+        "Given a list as input, the property is written once for each item, stopping at the \
+            first error. Records take the place of the arguments by their columns (dest, object, \
+            interface, property, value and signature), and anything else is taken as the value.\n\n\
+            With --all-matching, returns a table of the outcome for each name instead of \
+            nothing."
+    }
+
     fn search_terms(&self) -> Vec<&str> {
         vec!["dbus", "property", "write", "put"]
     }
 
     fn examples(&self) -> Vec<Example<'_>> {
-        vec![Example {
-            example: "dbus set --dest=org.mpris.MediaPlayer2.spotify \
+        vec![
+            Example {
+                example: "dbus set --dest=org.mpris.MediaPlayer2.spotify \
                             /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player \
                             Volume 0.5",
-            description: "Set the volume of Spotify to 50%",
-            result: None,
-        }]
+                description: "Set the volume of Spotify to 50%",
+                result: None,
+            },
+            Example {
+                example: "[0.2 0.4 0.6 0.8] | each { |volume| sleep 1sec; $volume } | \
+                            dbus set --dest=org.mpris.MediaPlayer2.spotify \
+                            /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player Volume",
+                description: "Fade in the volume of Spotify",
+                result: None,
+            },
+            Example {
+                example: "dbus set --all-matching org.mpris.MediaPlayer2.* \
+                            /org/mpris/MediaPlayer2 org.mpris.MediaPlayer2.Player Volume 0.5",
+                description: "Set the volume of every media player to 50%",
+                result: None,
+            },
+        ]
     }
 
     fn run(
         &self,
-        _plugin: &Self::Plugin,
-        _engine: &EngineInterface,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
         call: &EvaluatedCall,
-        _input: &Value,
-    ) -> Result<Value, LabeledError> {
-        let config = DbusClientConfig::try_from(call)?;
-        let dbus = DbusClient::new(config)?;
-        dbus.set(
-            &call.get_flag("dest")?.unwrap(),
-            &call.req(0)?,
-            &call.req(1)?,
-            &call.req(2)?,
-            call.get_flag("signature")?.as_ref(),
-            &call.req(3)?,
+        input: PipelineData,
+    ) -> Result<PipelineData, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        let all_matching = super::all_matching_unless_dest(call)?;
+        let params = Params::from_call(
+            call,
+            &input,
+            &["object", "interface", "property", "value"],
+            &["dest", "signature"],
         )?;
-        Ok(Value::nothing(call.head))
+        let head = call.head;
+        let returns_table = all_matching.is_some();
+
+        // Each item of the input is either a record of parameters or a value to set
+        let set_item = move |item: Option<Value>| {
+            let params = params.with_item(item, "value")?;
+            let object = params.string("object")?;
+            let interface = params.string("interface")?;
+            let property = params.string("property")?;
+            let signature = params.opt_string("signature")?;
+            let value = params.value("value")?;
+            let set_dest = |dest: &Spanned<String>| {
+                dbus.set(
+                    dest,
+                    &object,
+                    &interface,
+                    &property,
+                    signature.as_ref(),
+                    value,
+                )
+                .map(|()| Value::nothing(head))
+            };
+
+            match (params.opt_string("dest")?, &all_matching) {
+                (Some(dest), _) => set_dest(&dest),
+                (None, Some(pattern)) => dbus.for_each_matching(pattern, head, set_dest),
+                (None, None) => Err(super::missing_dest(head)),
+            }
+        };
+
+        let results = super::input::for_each_item(engine, head, input, set_item)?;
+        if returns_table {
+            Ok(results)
+        } else {
+            // There's nothing to return for each item, but the first error stops the rest
+            results.drain()?;
+            Ok(PipelineData::Empty)
+        }
     }
 }
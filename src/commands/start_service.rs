@@ -0,0 +1,70 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Spanned, SyntaxShape, Type, Value};
+
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
+
+pub struct StartService;
+
+impl SimplePluginCommand for StartService {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus start-service"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .accepts_timeout()
+            .accepts_unsafe()
+            .input_output_type(Type::Nothing, Type::String)
+            .required(
+                "name",
+                SyntaxShape::String,
+                "The name of the service to start (see `dbus list --activatable`)",
+            )
+    }
+
+    fn description(&self) -> &str {
+        "Ask the bus to start the service for a name"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Returns `started` if the bus started the service, or `already running` if the name \
+            already had an owner."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "start", "activate", "service", "launch"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "dbus start-service org.freedesktop.Notifications",
+            description: "Make sure the notification daemon is running",
+            result: Some(Value::test_string("already running")),
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        let name: Spanned<String> = call.req(0)?;
+        let started = dbus.start_service(&name)?;
+        Ok(Value::string(
+            if started {
+                "started"
+            } else {
+                "already running"
+            },
+            call.head,
+        ))
+    }
+}
@@ -0,0 +1,57 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Type, Value};
+
+use crate::DbusSignatureUtilExt;
+
+pub struct TestBus;
+
+impl SimplePluginCommand for TestBus {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus test-bus"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .input_output_type(Type::Nothing, Type::Custom("dbus test-bus".into()))
+    }
+
+    fn description(&self) -> &str {
+        "Start a private message bus inside the plugin, for testing"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Returns a handle with the address of the bus, which can be used with --bus on the other \
+            commands. The bus supports name ownership, name lookup, signal subscriptions and \
+            file descriptor passing, but not service activation. \
+            It shuts down when the handle is no longer referenced."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "test", "bus", "daemon", "broker", "hermetic"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![Example {
+            example: "let bus = dbus test-bus; dbus list --bus $bus.address",
+            description: "Start a bus and list the names on it",
+            result: None,
+        }]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let bus = crate::test_bus::TestBus::start().map_err(|err| {
+            LabeledError::new(err.to_string()).with_label("while starting a test bus", call.head)
+        })?;
+        let handle = plugin.add_test_bus(engine, bus)?;
+        Ok(handle.into_value(call.head))
+    }
+}
@@ -0,0 +1,63 @@
+use nu_plugin::{EngineInterface, EvaluatedCall, SimplePluginCommand};
+use nu_protocol::{Example, LabeledError, Signature, Type, Value};
+
+use crate::{config::DbusClientConfig, DbusSignatureUtilExt};
+
+pub struct Whoami;
+
+impl SimplePluginCommand for Whoami {
+    type Plugin = crate::NuPluginDbus;
+
+    fn name(&self) -> &str {
+        "dbus whoami"
+    }
+
+    fn signature(&self) -> Signature {
+        Signature::build(self.name())
+            .dbus_command()
+            .accepts_dbus_client_options()
+            .accepts_timeout()
+            .input_output_type(Type::Nothing, Type::Record(vec![].into()))
+    }
+
+    fn description(&self) -> &str {
+        "Describe the connection to D-Bus and the bus it is connected to"
+    }
+
+    fn extra_description(&self) -> &str {
+        "Returns our unique name, the address in use, the bus and machine ids, the optional \
+            features and interfaces of the bus, and the well-known names we own. \
+            Anything the server doesn't provide is null."
+    }
+
+    fn search_terms(&self) -> Vec<&str> {
+        vec!["dbus", "whoami", "identity", "unique", "name", "bus", "id"]
+    }
+
+    fn examples(&self) -> Vec<Example<'_>> {
+        vec![
+            Example {
+                example: "dbus whoami --started | get address",
+                description: "Find out which bus started this process",
+                result: None,
+            },
+            Example {
+                example: "let conn = dbus connect; dbus whoami --conn $conn",
+                description: "Describe a dedicated connection",
+                result: None,
+            },
+        ]
+    }
+
+    fn run(
+        &self,
+        plugin: &Self::Plugin,
+        engine: &EngineInterface,
+        call: &EvaluatedCall,
+        _input: &Value,
+    ) -> Result<Value, LabeledError> {
+        let config = DbusClientConfig::new(engine, call)?;
+        let dbus = plugin.client(engine, config)?;
+        dbus.whoami(call.head)
+    }
+}
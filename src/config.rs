@@ -1,7 +1,14 @@
-use std::time::Duration;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
-use nu_plugin::EvaluatedCall;
-use nu_protocol::{LabeledError, Span, Spanned};
+use nu_plugin::{EngineInterface, EvaluatedCall};
+use nu_protocol::{LabeledError, Span, Spanned, Value};
+
+use crate::{
+    auth::AuthMechanism,
+    handle::DbusHandle,
+    pattern::Pattern,
+    redact::{Redaction, RedactionRule},
+};
 
 /// General configuration related to the D-Bus client connection
 #[derive(Debug, Clone)]
@@ -9,14 +16,43 @@ pub struct DbusClientConfig {
     pub span: Span,
     /// Which bus should we connect to?
     pub bus_choice: Spanned<DbusBusChoice>,
+    /// Whether the bus was chosen by a flag or the plugin config, rather than left as the default
+    pub bus_chosen: bool,
     /// How long to wait for a method call to return
     pub timeout: Spanned<Duration>,
     /// Enable introspection if signature unknown (default true)
     pub introspect: bool,
+    /// Don't ask for or wait for a reply to method calls (default false)
+    pub no_reply: bool,
+    /// Let the bus start the destination if it isn't running (default true)
+    pub auto_start: bool,
+    /// Let the destination ask the user to authorize the call, e.g. with polkit (default false)
+    pub interactive_auth: bool,
+    /// How to output unsigned 64-bit integers
+    pub u64_style: U64Style,
+    /// Print warnings, e.g. when introspection fails (default true)
+    pub warnings: bool,
+    /// Short names that can be used in place of a destination
+    pub aliases: HashMap<String, String>,
+    /// Authentication mechanisms to try in order, instead of letting libdbus choose
+    pub auth: Option<Spanned<Vec<AuthMechanism>>>,
+    /// How long to wait for the destination to appear on the bus before sending to it
+    pub wait_for_name: Option<Spanned<Duration>>,
+    /// When to try method calls again after they fail
+    pub retry: RetryPolicy,
+    /// Which methods may be called, unless overridden with `--unsafe`
+    pub safety: SafetyPolicy,
+    /// Where to record method calls that change something, as JSON Lines (signals aren't sent,
+    /// so there are none to record)
+    pub audit_log: Option<Spanned<PathBuf>>,
+    /// Which values to leave out of the audit log and error messages
+    pub redaction: Redaction,
+    /// The directory that relative paths, e.g. of files to send, are relative to
+    pub cwd: PathBuf,
 }
 
 /// Where to connect to the D-Bus server
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub enum DbusBusChoice {
     /// Connect to the session bus
     #[default]
@@ -25,29 +61,192 @@ pub enum DbusBusChoice {
     System,
     /// Connect to the bus that started this process
     Started,
+    /// Connect to the session bus of the user with the given uid
+    User(u32),
     /// Connect to a bus instance at the given address
     Bus(String),
     /// Connect to a non-bus D-Bus server at the given address (will not send Hello)
     Peer(String),
+    /// Use a connection opened by `dbus connect`, by its id
+    Connection(u64),
+}
+
+/// How to represent unsigned 64-bit integers, which don't always fit in a nushell `int`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum U64Style {
+    /// Always as a string
+    #[default]
+    String,
+    /// As an int if it fits, otherwise as a string
+    Int,
+}
+
+/// When and how often to try a method call again after it fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to try in total (default 1, i.e. don't retry)
+    pub attempts: u32,
+    /// How long to wait before the first retry, doubling after each one
+    pub backoff: Duration,
+    /// The names of the D-Bus errors that are worth retrying
+    ///
+    /// NoReply isn't one by default: the call may have been carried out, and sending it again
+    /// could do it twice.
+    pub errors: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(100),
+            errors: [
+                "org.freedesktop.DBus.Error.ServiceUnknown",
+                "org.freedesktop.DBus.Error.NameHasNoOwner",
+            ]
+            .map(String::from)
+            .into(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether the error should be retried after the given number of attempts
+    pub fn should_retry(&self, attempt: u32, err: &dbus::Error) -> bool {
+        attempt < self.attempts
+            && err
+                .name()
+                .is_some_and(|name| self.errors.iter().any(|e| e == name))
+    }
+}
+
+/// Which methods may be called, to avoid changing anything by accident
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SafetyPolicy {
+    /// Only allow methods that just read something, and those on the allowlist (default false)
+    pub read_only: bool,
+    /// Patterns of `interface.method` that are also allowed in read-only mode
+    pub allow: Vec<String>,
+    /// Patterns of `interface.method` that are never allowed, even if they read only or `--unsafe`
+    /// is given
+    pub deny: Vec<String>,
+}
+
+impl SafetyPolicy {
+    /// The methods allowed in read-only mode, as `interface.method` patterns
+    pub const READ_ONLY_METHODS: &[&str] = &[
+        "org.freedesktop.DBus.Introspectable.Introspect",
+        "org.freedesktop.DBus.Properties.Get",
+        "org.freedesktop.DBus.Properties.GetAll",
+        "org.freedesktop.DBus.Peer.*",
+        "org.freedesktop.DBus.GetId",
+        "org.freedesktop.DBus.ListNames",
+        "org.freedesktop.DBus.ListActivatableNames",
+        "org.freedesktop.DBus.ListQueuedOwners",
+        "org.freedesktop.DBus.NameHasOwner",
+        "org.freedesktop.DBus.GetNameOwner",
+        "org.freedesktop.DBus.GetConnection*",
+        "org.freedesktop.DBus.GetAdtAuditSessionData",
+    ];
+
+    /// Check whether a method may be called, returning the reason if it may not
+    pub fn refusal(&self, interface: &str, method: &str) -> Option<String> {
+        let name = format!("{interface}.{method}");
+        let matches = |pattern: &str| Pattern::new(pattern, Some('.')).is_match(&name);
+
+        if let Some(pattern) = self.deny.iter().find(|pattern| matches(pattern)) {
+            Some(format!("{name} is denied by the pattern {pattern:?}"))
+        } else if self.read_only
+            && !Self::is_read(interface, method)
+            && !self.allow.iter().any(|pattern| matches(pattern))
+        {
+            Some(format!("{name} isn't allowed in read-only mode"))
+        } else {
+            None
+        }
+    }
+
+    /// Whether a method only reads something, i.e. is one of the
+    /// [`READ_ONLY_METHODS`](Self::READ_ONLY_METHODS)
+    ///
+    /// Methods on the allowlist don't count, as they're allowed despite changing something.
+    pub fn is_read(interface: &str, method: &str) -> bool {
+        let name = format!("{interface}.{method}");
+        Self::READ_ONLY_METHODS
+            .iter()
+            .any(|pattern| Pattern::new(pattern, Some('.')).is_match(&name))
+    }
 }
 
-impl TryFrom<&EvaluatedCall> for DbusClientConfig {
-    type Error = LabeledError;
+impl DbusBusChoice {
+    /// Determine the address that will be connected to, the same way libdbus does
+    pub fn address(&self) -> Option<String> {
+        let env = |name: &str| std::env::var(name).ok();
+        match self {
+            DbusBusChoice::Session => env("DBUS_SESSION_BUS_ADDRESS"),
+            DbusBusChoice::System => env("DBUS_SYSTEM_BUS_ADDRESS")
+                .or_else(|| Some("unix:path=/var/run/dbus/system_bus_socket".into())),
+            DbusBusChoice::Started => env("DBUS_STARTER_ADDRESS"),
+            DbusBusChoice::User(uid) => Some(format!("unix:path=/run/user/{uid}/bus")),
+            DbusBusChoice::Bus(address) | DbusBusChoice::Peer(address) => Some(address.clone()),
+            DbusBusChoice::Connection(_) => None,
+        }
+    }
+}
 
-    fn try_from(call: &EvaluatedCall) -> Result<Self, Self::Error> {
+impl DbusClientConfig {
+    /// Determine the config from the plugin configuration (`$env.config.plugins.dbus`), with the
+    /// command's flags taking precedence
+    pub fn new(engine: &EngineInterface, call: &EvaluatedCall) -> Result<Self, LabeledError> {
+        let plugin_config = engine.get_plugin_config()?;
+        let mut config = DbusClientConfig::from_call(plugin_config.as_ref(), call)?;
+        config.cwd = engine.get_current_dir()?.into();
+        Ok(config)
+    }
+
+    #[cfg(test)]
+    pub fn for_test(bus_choice: DbusBusChoice) -> Self {
+        let mut config = DbusClientConfig::from_call(None, &EvaluatedCall::new(Span::test_data()))
+            .expect("default config should be valid");
+        config.bus_choice.item = bus_choice;
+        config
+    }
+
+    fn from_call(
+        plugin_config: Option<&Value>,
+        call: &EvaluatedCall,
+    ) -> Result<Self, LabeledError> {
         let mut config = DbusClientConfig {
             span: call.head,
             bus_choice: Spanned {
                 item: DbusBusChoice::default(),
                 span: call.head,
             },
+            bus_chosen: false,
             timeout: Spanned {
                 item: Duration::from_secs(2),
                 span: call.head,
             },
             introspect: true,
+            no_reply: false,
+            auto_start: true,
+            interactive_auth: false,
+            u64_style: U64Style::default(),
+            warnings: true,
+            aliases: HashMap::new(),
+            auth: None,
+            wait_for_name: None,
+            retry: RetryPolicy::default(),
+            safety: SafetyPolicy::default(),
+            audit_log: None,
+            redaction: Redaction::default(),
+            cwd: std::env::current_dir().unwrap_or_default(),
         };
 
+        if let Some(plugin_config) = plugin_config {
+            config.apply_plugin_config(plugin_config)?;
+        }
+
         // Handle recognized config args
         for (name, value) in &call.named {
             match &name.item[..] {
@@ -63,6 +262,7 @@ impl TryFrom<&EvaluatedCall> for DbusClientConfig {
                             item: dest,
                             span: name.span,
                         };
+                        config.bus_chosen = true;
                     }
                 }
                 r#type @ ("bus" | "peer") => {
@@ -77,26 +277,102 @@ impl TryFrom<&EvaluatedCall> for DbusClientConfig {
                             item: dest,
                             span: value.span(),
                         };
+                        config.bus_chosen = true;
                     }
                 }
-                "timeout" => {
+                "user" => {
                     if let Some(value) = value {
-                        let nanos: u64 = value.as_duration()?.try_into().map_err(|_| {
-                            LabeledError::new("Timeout must be a positive duration")
-                                .with_label("invalid timeout specified here", value.span())
+                        let uid = value.as_int()?.try_into().map_err(|_| {
+                            LabeledError::new("Invalid user id")
+                                .with_label("expected a non-negative uid", value.span())
                         })?;
-                        let item = Duration::from_nanos(nanos);
+                        config.bus_choice = Spanned {
+                            item: DbusBusChoice::User(uid),
+                            span: value.span(),
+                        };
+                        config.bus_chosen = true;
+                    }
+                }
+                "auth" => {
+                    if let Some(value) = value {
+                        config.auth = Some(Spanned {
+                            item: auth_from_value(value)?,
+                            span: value.span(),
+                        });
+                    }
+                }
+                "conn" => {
+                    if let Some(value) = value {
+                        let id = match value
+                            .as_custom_value()?
+                            .as_any()
+                            .downcast_ref::<DbusHandle>()
+                        {
+                            Some(DbusHandle::Connection { id, .. }) => *id,
+                            _ => {
+                                return Err(LabeledError::new("Expected a D-Bus connection")
+                                    .with_label(
+                                        "this should come from `dbus connect`",
+                                        value.span(),
+                                    ))
+                            }
+                        };
+                        config.bus_choice = Spanned {
+                            item: DbusBusChoice::Connection(id),
+                            span: value.span(),
+                        };
+                        config.bus_chosen = true;
+                    }
+                }
+                "timeout" => {
+                    if let Some(value) = value {
                         config.timeout = Spanned {
-                            item,
+                            item: timeout_from_value(value)?,
                             span: value.span(),
                         };
                     }
                 }
+                "wait-for-name" => {
+                    if let Some(value) = value {
+                        config.wait_for_name = Some(Spanned {
+                            item: timeout_from_value(value)?,
+                            span: value.span(),
+                        });
+                    }
+                }
+                "retries" => {
+                    if let Some(value) = value {
+                        let retries: u32 = value.as_int()?.try_into().map_err(|_| {
+                            LabeledError::new("Invalid number of retries")
+                                .with_label("expected a non-negative number", value.span())
+                        })?;
+                        config.retry.attempts = retries.saturating_add(1);
+                    }
+                }
                 "no-introspect" => {
-                    config.introspect = !value
-                        .as_ref()
-                        .and_then(|v| v.as_bool().ok())
-                        .unwrap_or(false);
+                    if value.as_ref().is_none_or(|v| v.is_true()) {
+                        config.introspect = false;
+                    }
+                }
+                "interactive-auth" => {
+                    if value.as_ref().is_none_or(|v| v.is_true()) {
+                        config.interactive_auth = true;
+                    }
+                }
+                "no-reply" => {
+                    if value.as_ref().is_none_or(|v| v.is_true()) {
+                        config.no_reply = true;
+                    }
+                }
+                "no-auto-start" => {
+                    if value.as_ref().is_none_or(|v| v.is_true()) {
+                        config.auto_start = false;
+                    }
+                }
+                "unsafe" => {
+                    if value.as_ref().is_none_or(|v| v.is_true()) {
+                        config.safety.read_only = false;
+                    }
                 }
                 _ => (),
             }
@@ -104,4 +380,467 @@ impl TryFrom<&EvaluatedCall> for DbusClientConfig {
 
         Ok(config)
     }
+
+    /// Use the settings from the plugin configuration as defaults
+    fn apply_plugin_config(&mut self, plugin_config: &Value) -> Result<(), LabeledError> {
+        let invalid = |msg: &str, value: &Value| {
+            LabeledError::new("Invalid D-Bus plugin configuration")
+                .with_label(msg, value.span())
+                .with_help("check the settings in $env.config.plugins.dbus")
+        };
+
+        for (key, value) in plugin_config.as_record()? {
+            // Treat null as unset
+            if value.is_nothing() {
+                continue;
+            }
+            match &key[..] {
+                "bus" => {
+                    let item = match value.as_str()? {
+                        "session" => DbusBusChoice::Session,
+                        "system" => DbusBusChoice::System,
+                        "started" => DbusBusChoice::Started,
+                        _ => {
+                            return Err(invalid("expected one of: session, system, started", value))
+                        }
+                    };
+                    self.bus_choice = Spanned {
+                        item,
+                        span: value.span(),
+                    };
+                    self.bus_chosen = true;
+                }
+                "address" => {
+                    self.bus_choice = Spanned {
+                        item: DbusBusChoice::Bus(value.as_str()?.to_owned()),
+                        span: value.span(),
+                    };
+                    self.bus_chosen = true;
+                }
+                "auth" => {
+                    self.auth = Some(Spanned {
+                        item: auth_from_value(value)?,
+                        span: value.span(),
+                    });
+                }
+                "timeout" => {
+                    self.timeout = Spanned {
+                        item: timeout_from_value(value)?,
+                        span: value.span(),
+                    };
+                }
+                "wait_for_name" => {
+                    self.wait_for_name = Some(Spanned {
+                        item: timeout_from_value(value)?,
+                        span: value.span(),
+                    });
+                }
+                "retry" => {
+                    for (key, value) in value.as_record()? {
+                        match &key[..] {
+                            "attempts" => {
+                                self.retry.attempts = value
+                                    .as_int()?
+                                    .try_into()
+                                    .ok()
+                                    .filter(|attempts| *attempts > 0)
+                                    .ok_or_else(|| invalid("expected a positive number", value))?;
+                            }
+                            "backoff" => self.retry.backoff = timeout_from_value(value)?,
+                            "errors" => {
+                                self.retry.errors = value
+                                    .as_list()?
+                                    .iter()
+                                    .map(|name| name.coerce_string())
+                                    .collect::<Result<_, _>>()?;
+                            }
+                            other => {
+                                return Err(invalid(
+                                    &format!("unknown retry setting {other:?}"),
+                                    value,
+                                ));
+                            }
+                        }
+                    }
+                }
+                "safety" => {
+                    let patterns = |value: &Value| {
+                        value
+                            .as_list()?
+                            .iter()
+                            .map(|pattern| pattern.coerce_string())
+                            .collect::<Result<Vec<_>, _>>()
+                    };
+                    for (key, value) in value.as_record()? {
+                        match &key[..] {
+                            "read_only" => self.safety.read_only = value.as_bool()?,
+                            "allow" => self.safety.allow = patterns(value)?,
+                            "deny" => self.safety.deny = patterns(value)?,
+                            other => {
+                                return Err(invalid(
+                                    &format!("unknown safety setting {other:?}"),
+                                    value,
+                                ));
+                            }
+                        }
+                    }
+                }
+                "audit_log" => {
+                    let path = PathBuf::from(value.coerce_str()?.as_ref());
+                    if !path.is_absolute() {
+                        return Err(invalid("expected an absolute path", value));
+                    }
+                    self.audit_log = Some(Spanned {
+                        item: path,
+                        span: value.span(),
+                    });
+                }
+                "redact" => {
+                    self.redaction.rules = value
+                        .as_list()?
+                        .iter()
+                        .map(redaction_rule_from_value)
+                        .collect::<Result<_, _>>()?;
+                }
+                "introspect" => self.introspect = value.as_bool()?,
+                "auto_start" => self.auto_start = value.as_bool()?,
+                "u64" => {
+                    self.u64_style = match value.as_str()? {
+                        "string" => U64Style::String,
+                        "int" => U64Style::Int,
+                        _ => return Err(invalid("expected one of: string, int", value)),
+                    };
+                }
+                "warnings" => self.warnings = value.as_bool()?,
+                "aliases" => {
+                    for (alias, name) in value.as_record()? {
+                        self.aliases.insert(alias.clone(), name.coerce_string()?);
+                    }
+                }
+                other => {
+                    return Err(invalid(&format!("unknown setting {other:?}"), value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The longest a single method call can take: waiting for the destination, introspecting
+    /// it, and every attempt with the backoff between them
+    pub fn time_limit(&self) -> Duration {
+        let timeout = self.timeout.item;
+        let wait = self.wait_for_name.as_ref().map(|wait| wait.item);
+        let introspect = if self.introspect {
+            timeout
+        } else {
+            Duration::ZERO
+        };
+        let attempts = self.retry.attempts.max(1);
+        let backoff = self
+            .retry
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempts - 1) - 1);
+        wait.unwrap_or_default()
+            .saturating_add(introspect)
+            .saturating_add(timeout.saturating_mul(attempts))
+            .saturating_add(backoff)
+    }
+}
+
+/// Parse authentication mechanisms given as a list, or a string separated by commas or spaces
+fn auth_from_value(value: &Value) -> Result<Vec<AuthMechanism>, LabeledError> {
+    let names = match value {
+        Value::List { vals, .. } => vals
+            .iter()
+            .map(|val| val.coerce_string())
+            .collect::<Result<Vec<_>, _>>()?,
+        _ => value
+            .coerce_string()?
+            .split([',', ' '])
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_owned())
+            .collect(),
+    };
+    let expected = AuthMechanism::ALL
+        .map(|mechanism| mechanism.name())
+        .join(", ");
+    if names.is_empty() {
+        return Err(LabeledError::new("No authentication mechanisms specified")
+            .with_label(format!("expected one or more of: {expected}"), value.span()));
+    }
+    names
+        .iter()
+        .map(|name| {
+            AuthMechanism::from_name(name).ok_or_else(|| {
+                LabeledError::new(format!("Unknown authentication mechanism {name:?}"))
+                    .with_label(format!("expected one or more of: {expected}"), value.span())
+            })
+        })
+        .collect()
+}
+
+/// Parse a redaction rule given as a record of `interface`, `member` (default all), and
+/// optionally `arg` or `key`
+fn redaction_rule_from_value(value: &Value) -> Result<RedactionRule, LabeledError> {
+    let invalid = |msg: &str, value: &Value| {
+        LabeledError::new("Invalid D-Bus redaction rule")
+            .with_label(msg, value.span())
+            .with_help("check the redact setting in $env.config.plugins.dbus")
+    };
+    let mut rule = RedactionRule {
+        interface: String::new(),
+        member: "*".into(),
+        arg: None,
+        key: None,
+    };
+    let mut has_interface = false;
+    for (key, field) in value.as_record()? {
+        match &key[..] {
+            "interface" => {
+                rule.interface = field.coerce_string()?;
+                has_interface = true;
+            }
+            "member" | "method" | "property" => rule.member = field.coerce_string()?,
+            "arg" => {
+                rule.arg = Some(
+                    field
+                        .as_int()?
+                        .try_into()
+                        .map_err(|_| invalid("expected a non-negative index", field))?,
+                );
+            }
+            "key" => rule.key = Some(field.coerce_string()?),
+            other => return Err(invalid(&format!("unknown field {other:?}"), field)),
+        }
+    }
+    if !has_interface {
+        return Err(invalid("the interface is required", value));
+    }
+    Ok(rule)
+}
+
+fn timeout_from_value(value: &Value) -> Result<Duration, LabeledError> {
+    let nanos: u64 = value.as_duration()?.try_into().map_err(|_| {
+        LabeledError::new("Timeout must be a positive duration")
+            .with_label("invalid timeout specified here", value.span())
+    })?;
+    Ok(Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+fn test_plugin_config() -> Value {
+    Value::test_record(nu_protocol::record! {
+        "bus" => Value::test_string("system"),
+        "timeout" => Value::test_duration(10_000_000_000),
+        "introspect" => Value::test_bool(false),
+        "u64" => Value::test_string("int"),
+        "aliases" => Value::test_record(nu_protocol::record! {
+            "nm" => Value::test_string("org.freedesktop.NetworkManager"),
+        }),
+    })
+}
+
+#[test]
+fn test_config_defaults_from_plugin_config() {
+    let call = EvaluatedCall::new(Span::test_data());
+    let config = DbusClientConfig::from_call(Some(&test_plugin_config()), &call).unwrap();
+    assert_eq!(config.bus_choice.item, DbusBusChoice::System);
+    assert!(config.bus_chosen);
+    assert!(!DbusClientConfig::from_call(None, &call).unwrap().bus_chosen);
+    assert_eq!(config.timeout.item, Duration::from_secs(10));
+    assert!(!config.introspect);
+    assert_eq!(config.u64_style, U64Style::Int);
+    assert!(config.warnings);
+    assert_eq!(
+        config.aliases.get("nm").map(|s| &s[..]),
+        Some("org.freedesktop.NetworkManager")
+    );
+}
+
+#[test]
+fn test_config_flags_override_plugin_config() {
+    let call = EvaluatedCall::new(Span::test_data())
+        .with_flag(Spanned {
+            item: "session",
+            span: Span::test_data(),
+        })
+        .with_named(
+            Spanned {
+                item: "timeout",
+                span: Span::test_data(),
+            },
+            Value::test_duration(1_000_000_000),
+        );
+    let config = DbusClientConfig::from_call(Some(&test_plugin_config()), &call).unwrap();
+    assert_eq!(config.bus_choice.item, DbusBusChoice::Session);
+    assert_eq!(config.timeout.item, Duration::from_secs(1));
+}
+
+#[test]
+fn test_config_retry_policy() {
+    let plugin_config = Value::test_record(nu_protocol::record! {
+        "retry" => Value::test_record(nu_protocol::record! {
+            "backoff" => Value::test_duration(1_000_000),
+            "errors" => Value::test_list(vec![Value::test_string("com.example.Error.Busy")]),
+        }),
+    });
+    let call = EvaluatedCall::new(Span::test_data()).with_named(
+        Spanned {
+            item: "retries",
+            span: Span::test_data(),
+        },
+        Value::test_int(2),
+    );
+    let config = DbusClientConfig::from_call(Some(&plugin_config), &call).unwrap();
+    assert_eq!(
+        config.retry,
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            errors: vec!["com.example.Error.Busy".into()],
+        }
+    );
+
+    let busy = dbus::Error::new_custom("com.example.Error.Busy", "try again later");
+    let failed = dbus::Error::new_custom("org.freedesktop.DBus.Error.Failed", "no");
+    assert!(config.retry.should_retry(2, &busy));
+    assert!(!config.retry.should_retry(3, &busy));
+    assert!(!config.retry.should_retry(1, &failed));
+}
+
+#[test]
+fn test_config_safety_policy() {
+    let plugin_config = Value::test_record(nu_protocol::record! {
+        "safety" => Value::test_record(nu_protocol::record! {
+            "read_only" => Value::test_bool(true),
+            "allow" => Value::test_list(vec![
+                Value::test_string("org.freedesktop.systemd1.Manager.List*"),
+            ]),
+            "deny" => Value::test_list(vec![Value::test_string("org.freedesktop.DBus.Peer.*")]),
+        }),
+    });
+    let call = EvaluatedCall::new(Span::test_data());
+    let config = DbusClientConfig::from_call(Some(&plugin_config), &call).unwrap();
+    let safety = &config.safety;
+    assert!(safety
+        .refusal("org.freedesktop.DBus.Properties", "Get")
+        .is_none());
+    assert!(safety
+        .refusal("org.freedesktop.DBus.Properties", "Set")
+        .is_some());
+    assert!(safety
+        .refusal("org.freedesktop.systemd1.Manager", "ListUnits")
+        .is_none());
+    // Allowed, but not a read as far as the audit log is concerned
+    assert!(!SafetyPolicy::is_read(
+        "org.freedesktop.systemd1.Manager",
+        "ListUnits"
+    ));
+    assert!(safety
+        .refusal("org.freedesktop.systemd1.Manager", "StopUnit")
+        .is_some());
+    // The denylist wins over the read-only methods
+    assert!(safety
+        .refusal("org.freedesktop.DBus.Peer", "Ping")
+        .is_some());
+
+    // --unsafe lifts read-only mode, but not the denylist
+    let call = call.with_flag(Spanned {
+        item: "unsafe",
+        span: Span::test_data(),
+    });
+    let config = DbusClientConfig::from_call(Some(&plugin_config), &call).unwrap();
+    assert!(config
+        .safety
+        .refusal("org.freedesktop.DBus.Properties", "Set")
+        .is_none());
+    assert!(config
+        .safety
+        .refusal("org.freedesktop.DBus.Peer", "Ping")
+        .is_some());
+}
+
+#[test]
+fn test_config_redaction_rules() {
+    let plugin_config = Value::test_record(nu_protocol::record! {
+        "redact" => Value::test_list(vec![
+            Value::test_record(nu_protocol::record! {
+                "interface" => Value::test_string("org.freedesktop.Secret.*"),
+                "arg" => Value::test_int(1),
+            }),
+            Value::test_record(nu_protocol::record! {
+                "interface" => Value::test_string("org.freedesktop.NetworkManager.**"),
+                "method" => Value::test_string("Update*"),
+                "key" => Value::test_string("psk"),
+            }),
+        ]),
+    });
+    let call = EvaluatedCall::new(Span::test_data());
+    let config = DbusClientConfig::from_call(Some(&plugin_config), &call).unwrap();
+    assert_eq!(
+        config.redaction.rules,
+        vec![
+            RedactionRule {
+                interface: "org.freedesktop.Secret.*".into(),
+                member: "*".into(),
+                arg: Some(1),
+                key: None,
+            },
+            RedactionRule {
+                interface: "org.freedesktop.NetworkManager.**".into(),
+                member: "Update*".into(),
+                arg: None,
+                key: Some("psk".into()),
+            },
+        ]
+    );
+
+    let plugin_config = Value::test_record(nu_protocol::record! {
+        "redact" => Value::test_list(vec![Value::test_record(nu_protocol::record! {
+            "member" => Value::test_string("Unlock"),
+        })]),
+    });
+    assert!(DbusClientConfig::from_call(Some(&plugin_config), &call).is_err());
+}
+
+#[test]
+fn test_config_rejects_unknown_setting() {
+    let plugin_config = Value::test_record(nu_protocol::record! {
+        "timeuot" => Value::test_duration(1_000_000_000),
+    });
+    let call = EvaluatedCall::new(Span::test_data());
+    assert!(DbusClientConfig::from_call(Some(&plugin_config), &call).is_err());
+}
+
+#[test]
+fn test_safety_policy_refuses_calls() {
+    use crate::client::{bus_call, spanned, TestClient};
+
+    let test = TestClient::start(|config| {
+        config.introspect = false;
+        config.safety.read_only = true;
+    });
+    let client = test.client();
+
+    bus_call(&client, "org.freedesktop.DBus.Peer", "Ping", "", &[]).unwrap();
+    bus_call(&client, "org.freedesktop.DBus", "ListNames", "", &[]).unwrap();
+    let err = bus_call(
+        &client,
+        "org.freedesktop.DBus",
+        "RequestName",
+        "su",
+        &[Value::test_string("com.example.Mine"), Value::test_int(0)],
+    )
+    .unwrap_err();
+    assert_eq!(err.msg, "Refused by the D-Bus safety policy");
+    assert!(client
+        .set(
+            &spanned("org.freedesktop.DBus"),
+            &spanned("/org/freedesktop/DBus"),
+            &spanned("org.freedesktop.DBus"),
+            &spanned("Features"),
+            None,
+            &Value::test_list(vec![]),
+        )
+        .is_err_and(|err| err.msg == "Refused by the D-Bus safety policy"));
 }
@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use dbus::channel::{BusType, Channel};
+use nu_protocol::{LabeledError, Span, Spanned};
+
+use crate::{
+    address::DbusAddress,
+    auth::{current_uid, AuthMechanism},
+    config::DbusBusChoice,
+    relay,
+};
+
+/// An open connection to a D-Bus server, which may be shared between commands
+pub struct DbusConnection {
+    channel: Channel,
+    address: String,
+    /// How many calls from `dbus call --async` are waiting for a reply
+    background_calls: AtomicUsize,
+    /// How the connection was opened, so that another one can be opened the same way
+    origin: Option<Origin>,
+}
+
+/// The bus choice and authentication mechanisms a connection was opened with
+type Origin = (Spanned<DbusBusChoice>, Option<Spanned<Vec<AuthMechanism>>>);
+
+/// Marks a call running in the background on a connection, until dropped
+pub struct BackgroundCall(Arc<DbusConnection>);
+
+impl Drop for BackgroundCall {
+    fn drop(&mut self) {
+        self.0.background_calls.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DbusConnection {
+    /// Connect to the D-Bus server specified by the bus choice, optionally choosing how to
+    /// authenticate
+    pub fn open(
+        bus_choice: &Spanned<DbusBusChoice>,
+        auth: Option<&Spanned<Vec<AuthMechanism>>>,
+    ) -> Result<DbusConnection, LabeledError> {
+        let mut conn = DbusConnection::connect(bus_choice, auth)?;
+        conn.origin = Some((bus_choice.clone(), auth.cloned()));
+        Ok(conn)
+    }
+
+    /// Open a separate connection to the same server, in the same way as this one
+    ///
+    /// Anything that reads messages off the queue itself, rather than waiting for a reply, needs
+    /// a connection of its own. Otherwise it could take the replies to calls made at the same
+    /// time on a shared connection.
+    pub fn open_another(&self) -> Result<DbusConnection, LabeledError> {
+        match &self.origin {
+            Some((bus_choice, auth)) => DbusConnection::open(bus_choice, auth.as_ref()),
+            None => Err(LabeledError::new(
+                "Can't open another connection like this one",
+            )),
+        }
+    }
+
+    fn connect(
+        bus_choice: &Spanned<DbusBusChoice>,
+        auth: Option<&Spanned<Vec<AuthMechanism>>>,
+    ) -> Result<DbusConnection, LabeledError> {
+        if let Some(auth) = auth {
+            if !matches!(
+                bus_choice.item,
+                DbusBusChoice::Bus(_) | DbusBusChoice::Peer(_)
+            ) {
+                return Err(LabeledError::new("Can't choose how to authenticate here")
+                    .with_label("--auth can only be used with --bus or --peer", auth.span));
+            }
+        }
+        let auth = auth.map(|auth| &auth.item[..]);
+        let connect_error = |err: dbus::Error| {
+            LabeledError::new(err.to_string()).with_label(
+                "while connecting to D-Bus as specified here",
+                bus_choice.span,
+            )
+        };
+        let channel = match &bus_choice.item {
+            DbusBusChoice::Session if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() => {
+                return open_session_bus(None, bus_choice.span);
+            }
+            DbusBusChoice::Session => {
+                Channel::get_private(BusType::Session).map_err(connect_error)?
+            }
+            DbusBusChoice::User(uid) => {
+                return open_session_bus(Some(*uid), bus_choice.span);
+            }
+            DbusBusChoice::System => {
+                Channel::get_private(BusType::System).map_err(connect_error)?
+            }
+            DbusBusChoice::Started => {
+                Channel::get_private(BusType::Starter).map_err(connect_error)?
+            }
+            DbusBusChoice::Peer(address) => {
+                return open_any(address, bus_choice.span, false, auth);
+            }
+            DbusBusChoice::Bus(address) => {
+                return open_any(address, bus_choice.span, true, auth);
+            }
+            DbusBusChoice::Connection(_) => {
+                return Err(
+                    LabeledError::new("This connection has been closed").with_label(
+                        "connections from `dbus connect` can't be reopened",
+                        bus_choice.span,
+                    ),
+                )
+            }
+        };
+        let address = bus_choice.item.address().unwrap_or_default();
+        Ok(DbusConnection::new(channel, address))
+    }
+
+    fn new(channel: Channel, address: String) -> DbusConnection {
+        DbusConnection {
+            channel,
+            address,
+            background_calls: AtomicUsize::new(0),
+            origin: None,
+        }
+    }
+
+    /// Note that a call is running in the background, so that its reply isn't mistaken for an
+    /// unsolicited message and thrown away
+    pub fn background_call(self: &Arc<Self>) -> BackgroundCall {
+        self.background_calls.fetch_add(1, Ordering::Relaxed);
+        BackgroundCall(self.clone())
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    /// The address of the server we connected to
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The unique name assigned to us by the bus, if this is a bus connection
+    pub fn unique_name(&self) -> Option<&str> {
+        self.channel.unique_name()
+    }
+
+    /// Check whether the connection is still usable
+    pub fn is_healthy(&self) -> bool {
+        // Process any pending I/O without blocking, so that a closed socket is noticed
+        if self.channel.read_write(Some(Duration::ZERO)).is_err() {
+            return false;
+        }
+        // Nobody is waiting for unsolicited messages (e.g. NameAcquired), so don't let them pile up.
+        // Replies are queued with them though, so leave them while a call is in the background.
+        if self.background_calls.load(Ordering::Relaxed) == 0 {
+            while self.channel.pop_message().is_some() {}
+        }
+        self.channel.is_connected()
+    }
+}
+
+/// Connect to the first of a `;`-separated list of addresses that works
+fn open_any(
+    address: &str,
+    span: Span,
+    register: bool,
+    auth: Option<&[AuthMechanism]>,
+) -> Result<DbusConnection, LabeledError> {
+    let addresses = DbusAddress::parse_list(address).map_err(|err| {
+        err.into_labeled(&Spanned {
+            item: address,
+            span,
+        })
+    })?;
+
+    open_first(&addresses, register, auth).map_err(|failures| {
+        if let [(_, err)] = failures.as_slice() {
+            LabeledError::new(err.clone())
+                .with_label("while connecting to D-Bus as specified here", span)
+        } else {
+            LabeledError::new("Couldn't connect to any of the addresses")
+                .with_label("while connecting to D-Bus as specified here", span)
+                .with_help(describe_failures(&failures))
+        }
+    })
+}
+
+/// Connect to a user's session bus without `DBUS_SESSION_BUS_ADDRESS`, by looking in the usual
+/// places for it
+fn open_session_bus(uid: Option<u32>, span: Span) -> Result<DbusConnection, LabeledError> {
+    let own_uid = current_uid();
+    let uid = uid.unwrap_or(own_uid);
+
+    open_first(&session_bus_candidates(uid), true, None).map_err(|failures| {
+        let msg = if uid == own_uid {
+            "Couldn't find the session bus".into()
+        } else {
+            format!("Couldn't find the session bus of user {uid}")
+        };
+        LabeledError::new(msg)
+            .with_label("while connecting to D-Bus as specified here", span)
+            .with_help(format!(
+                "tried these addresses:\n{}",
+                describe_failures(&failures)
+            ))
+    })
+}
+
+/// The addresses where a user's session bus might be, in the order they should be tried
+pub fn session_bus_candidates(uid: u32) -> Vec<DbusAddress> {
+    let mut candidates = vec![];
+    if uid == current_uid() {
+        if let Some(address) = std::env::var_os("DBUS_SESSION_BUS_ADDRESS") {
+            candidates
+                .extend(DbusAddress::parse_list(&address.to_string_lossy()).unwrap_or_default());
+        }
+        if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+            candidates.push(DbusAddress::unix_path(Path::new(&dir).join("bus")));
+        }
+    }
+    let default = DbusAddress::unix_path(format!("/run/user/{uid}/bus"));
+    if !candidates.contains(&default) {
+        candidates.push(default);
+    }
+    candidates
+}
+
+/// Connect to the first address that works, or return why each of them didn't
+fn open_first(
+    addresses: &[DbusAddress],
+    register: bool,
+    auth: Option<&[AuthMechanism]>,
+) -> Result<DbusConnection, Vec<(String, String)>> {
+    let mut failures = vec![];
+    for address in addresses {
+        let result = address.check_connectable().and_then(|()| {
+            let mut channel = match auth {
+                Some(mechanisms) => relay::open_with_auth(address, mechanisms)?,
+                None => {
+                    Channel::open_private(&address.to_string()).map_err(|err| err.to_string())?
+                }
+            };
+            if register {
+                channel.register().map_err(|err| err.to_string())?;
+            }
+            Ok(channel)
+        });
+        let address = address.to_string();
+        match result {
+            Ok(channel) => return Ok(DbusConnection::new(channel, address)),
+            Err(err) => failures.push((address, err)),
+        }
+    }
+    Err(failures)
+}
+
+fn describe_failures(failures: &[(String, String)]) -> String {
+    failures
+        .iter()
+        .map(|(address, err)| format!("{address}: {err}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Connections kept open between commands, so that each command doesn't have to connect and
+/// authenticate again
+#[derive(Default)]
+pub struct ConnectionPool {
+    conns: Mutex<HashMap<PoolKey, Arc<DbusConnection>>>,
+}
+
+/// Connections that authenticated differently are kept apart
+type PoolKey = (DbusBusChoice, Option<Vec<AuthMechanism>>);
+
+impl ConnectionPool {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PoolKey, Arc<DbusConnection>>> {
+        self.conns.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Get a working connection for the bus choice, reconnecting if the pooled one has failed
+    pub fn get(
+        &self,
+        bus_choice: &Spanned<DbusBusChoice>,
+        auth: Option<&Spanned<Vec<AuthMechanism>>>,
+    ) -> Result<Arc<DbusConnection>, LabeledError> {
+        let key = (bus_choice.item.clone(), auth.map(|auth| auth.item.clone()));
+        if let Some(conn) = self.lock().get(&key) {
+            if conn.is_healthy() {
+                return Ok(conn.clone());
+            }
+        }
+
+        // Don't hold the lock while connecting, as that can take a while
+        let conn = Arc::new(DbusConnection::open(bus_choice, auth)?);
+        self.lock().insert(key, conn.clone());
+        Ok(conn)
+    }
+
+    /// Close the pooled connections for the bus choice
+    pub fn remove(&self, bus_choice: &DbusBusChoice) {
+        self.lock().retain(|(choice, _), _| choice != bus_choice);
+    }
+
+    /// Close all pooled connections
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+#[test]
+fn test_pool_reuses_healthy_connections() {
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus(bus.address().into()),
+        span: nu_protocol::Span::test_data(),
+    };
+    let pool = ConnectionPool::default();
+
+    let first = pool.get(&bus_choice, None).unwrap();
+    let second = pool.get(&bus_choice, None).unwrap();
+    assert!(Arc::ptr_eq(&first, &second));
+
+    // After the bus goes away, the pooled connection should be noticed as broken
+    drop(bus);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!first.is_healthy());
+    assert!(pool.get(&bus_choice, None).is_err());
+}
+
+#[test]
+fn test_health_check_keeps_replies_for_background_calls() {
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus(bus.address().into()),
+        span: nu_protocol::Span::test_data(),
+    };
+    let conn = Arc::new(DbusConnection::open(&bus_choice, None).unwrap());
+    let ping = || {
+        let message = dbus::Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus.Peer",
+            "Ping",
+        )
+        .unwrap();
+        let serial = conn.channel().send(message).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(conn.is_healthy());
+        std::iter::from_fn(|| conn.channel().pop_message())
+            .any(|message| message.get_reply_serial() == Some(serial))
+    };
+
+    let background = conn.background_call();
+    assert!(ping(), "the reply should be left for the background call");
+    drop(background);
+    assert!(!ping(), "unsolicited messages should be discarded");
+}
+
+#[test]
+fn test_open_fails_over_to_next_address() {
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let span = nu_protocol::Span::test_data();
+    let bus_choice = |address: String| Spanned {
+        item: DbusBusChoice::Bus(address),
+        span,
+    };
+
+    let conn = DbusConnection::open(
+        &bus_choice(format!(
+            "unix:path=/nonexistent/nu_plugin_dbus;unix:tmpdir=/tmp;{}",
+            bus.address()
+        )),
+        None,
+    )
+    .unwrap();
+    assert_eq!(conn.address(), bus.address());
+    assert!(conn.unique_name().is_some());
+
+    let err = DbusConnection::open(
+        &bus_choice("unix:path=/nonexistent/nu_plugin_dbus;unix:tmpdir=/tmp".into()),
+        None,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.msg, "Couldn't connect to any of the addresses");
+
+    assert!(DbusConnection::open(&bus_choice("unix:path=/a,bogus=1".into()), None).is_err());
+}
+
+#[test]
+fn test_open_unixexec_through_bridge() {
+    use crate::{address::DbusAddress, auth::AuthMechanism};
+
+    // Built along with the tests, into target/<profile>/examples
+    let bridge = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .and_then(|deps| deps.parent())
+        .unwrap()
+        .join("examples/stdio_bridge");
+    assert!(
+        bridge.exists(),
+        "{} is missing, build it with `cargo build --examples`",
+        bridge.display()
+    );
+
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let address = DbusAddress {
+        transport: "unixexec".into(),
+        params: vec![
+            ("path".into(), bridge.to_string_lossy().into_owned()),
+            ("argv0".into(), "stdio-bridge".into()),
+            (
+                "argv1".into(),
+                bus.socket_path().to_string_lossy().into_owned(),
+            ),
+        ],
+    };
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus(address.to_string()),
+        span: nu_protocol::Span::test_data(),
+    };
+
+    // Spawned by libdbus
+    let conn = DbusConnection::open(&bus_choice, None).unwrap();
+    assert!(conn.unique_name().is_some_and(|name| name.starts_with(':')));
+
+    // Spawned by the relay, which authenticates over the bridge's stdio itself
+    let auth = Spanned {
+        item: vec![AuthMechanism::Anonymous],
+        span: nu_protocol::Span::test_data(),
+    };
+    let conn = DbusConnection::open(&bus_choice, Some(&auth)).unwrap();
+    assert!(conn.unique_name().is_some_and(|name| name.starts_with(':')));
+}
+
+#[test]
+fn test_open_user_bus_lists_candidates() {
+    let err = DbusConnection::open(
+        &Spanned {
+            item: DbusBusChoice::User(4_000_000_000),
+            span: nu_protocol::Span::test_data(),
+        },
+        None,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.msg, "Couldn't find the session bus of user 4000000000");
+    assert!(err
+        .help
+        .unwrap()
+        .contains("unix:path=/run/user/4000000000/bus: "));
+}
+
+#[test]
+fn test_open_with_auth() {
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let span = nu_protocol::Span::test_data();
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus(bus.address().into()),
+        span,
+    };
+    let auth = |mechanisms: Vec<AuthMechanism>| Spanned {
+        item: mechanisms,
+        span,
+    };
+
+    // The test bus accepts ANONYMOUS, and the connection should work through the relay
+    let conn = DbusConnection::open(
+        &bus_choice,
+        Some(&auth(vec![
+            AuthMechanism::CookieSha1,
+            AuthMechanism::Anonymous,
+        ])),
+    )
+    .unwrap();
+    assert!(conn.unique_name().is_some());
+    let reply = conn
+        .channel()
+        .send_with_reply_and_block(
+            dbus::Message::new_method_call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "GetId",
+            )
+            .unwrap(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+    assert!(reply.get1::<&str>().is_some());
+
+    let err = DbusConnection::open(&bus_choice, Some(&auth(vec![AuthMechanism::CookieSha1])))
+        .err()
+        .unwrap();
+    assert!(
+        err.msg.contains("the server offered: EXTERNAL, ANONYMOUS"),
+        "{}",
+        err.msg
+    );
+
+    let session = Spanned {
+        item: DbusBusChoice::Session,
+        span,
+    };
+    assert!(DbusConnection::open(&session, Some(&auth(vec![AuthMechanism::External]))).is_err());
+}
@@ -1,25 +1,52 @@
 use dbus::{
     arg::{
         messageitem::{MessageItem, MessageItemArray, MessageItemDict},
-        ArgType, RefArg,
+        ArgType, OwnedFd, RefArg,
     },
     Message, Signature,
 };
 use nu_protocol::{LabeledError, Record, Span, Value};
-use std::str::FromStr;
+use std::{
+    fs::File,
+    os::fd::{BorrowedFd, FromRawFd, IntoRawFd, RawFd},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{config::U64Style, dbus_type::DbusType, fd::FdStore};
 
-use crate::dbus_type::DbusType;
+/// Where the files to send as file descriptors come from
+#[derive(Clone, Copy)]
+pub struct FdSource<'a> {
+    /// The directory that relative paths are relative to, usually the engine's
+    pub cwd: &'a Path,
+    /// The file descriptors that handles refer to
+    pub fds: &'a FdStore,
+}
 
 /// Get the arguments of a message as nushell Values
-pub fn from_message(message: &Message, span: Span) -> Result<Vec<Value>, String> {
+///
+/// File descriptors are kept open in `fds`, and given as handles to them. Without a store, they
+/// are just described.
+pub fn from_message(
+    message: &Message,
+    span: Span,
+    u64_style: U64Style,
+    fds: Option<&FdStore>,
+) -> Result<Vec<Value>, String> {
     let mut out = vec![];
     for refarg in message.iter_init() {
-        out.push(from_refarg(&refarg, span)?);
+        out.push(from_refarg(&refarg, span, u64_style, fds)?);
     }
     Ok(out)
 }
 
-pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
+pub fn from_refarg(
+    refarg: &dyn RefArg,
+    span: Span,
+    u64_style: U64Style,
+    fds: Option<&FdStore>,
+) -> Result<Value, String> {
     Ok(match refarg.arg_type() {
         ArgType::Array => {
             if refarg.signature().starts_with("a{") {
@@ -29,7 +56,7 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
                 while let Some(key) = iter.next() {
                     if let Some(val) = iter.next() {
                         if let Some(key_str) = key.as_str() {
-                            record.insert(key_str, from_refarg(val, span)?);
+                            record.insert(key_str, from_refarg(val, span, u64_style, fds)?);
                         }
                     }
                 }
@@ -46,7 +73,7 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
                     refarg
                         .as_iter()
                         .unwrap()
-                        .flat_map(|v| from_refarg(v, span))
+                        .flat_map(|v| from_refarg(v, span, u64_style, fds))
                         .collect(),
                     span,
                 )
@@ -54,7 +81,7 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
         }
         ArgType::Variant => {
             let inner = refarg.as_iter().unwrap().next().unwrap();
-            return from_refarg(inner, span);
+            return from_refarg(inner, span, u64_style, fds);
         }
         ArgType::Boolean => Value::bool(refarg.as_i64().unwrap() != 0, span),
 
@@ -68,11 +95,29 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
         | ArgType::UInt16
         | ArgType::Int32
         | ArgType::UInt32
-        | ArgType::Int64
-        | ArgType::UnixFd => Value::int(refarg.as_i64().unwrap(), span),
+        | ArgType::Int64 => Value::int(refarg.as_i64().unwrap(), span),
 
-        // Nushell doesn't support u64, so present it as a string
-        ArgType::UInt64 => Value::string(refarg.as_u64().unwrap().to_string(), span),
+        // File descriptors, which are only valid for as long as they're kept open
+        ArgType::UnixFd => match fds {
+            Some(fds) => {
+                // SAFETY: the fd belongs to the argument, which outlives this borrow of it
+                let fd = unsafe { BorrowedFd::borrow_raw(refarg.as_i64().unwrap() as RawFd) };
+                let file = fd
+                    .try_clone_to_owned()
+                    .map_err(|err| format!("Failed to keep a file descriptor open: {err}"))?;
+                fds.keep(file.into(), span)
+            }
+            None => Value::string("<unix fd>", span),
+        },
+
+        // Nushell doesn't support u64, so present it as a string unless configured otherwise
+        ArgType::UInt64 => {
+            let val = refarg.as_u64().unwrap();
+            match (u64_style, i64::try_from(val)) {
+                (U64Style::Int, Ok(int)) => Value::int(int, span),
+                _ => Value::string(val.to_string(), span),
+            }
+        }
 
         // Floats
         ArgType::Double => Value::float(refarg.as_f64().unwrap(), span),
@@ -81,7 +126,7 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
             refarg
                 .as_iter()
                 .unwrap()
-                .flat_map(|v| from_refarg(v, span))
+                .flat_map(|v| from_refarg(v, span, u64_style, fds))
                 .collect(),
             span,
         ),
@@ -96,6 +141,7 @@ pub fn from_refarg(refarg: &dyn RefArg, span: Span) -> Result<Value, String> {
 pub fn to_message_item(
     value: &Value,
     expected_type: Option<&DbusType>,
+    fd_source: FdSource,
 ) -> Result<MessageItem, LabeledError> {
     // Report errors from conversion. Error must support Display
     macro_rules! try_convert {
@@ -176,6 +222,30 @@ pub fn to_message_item(
             Ok(MessageItem::Double(try_convert!(f64::from_str(&val[..]))))
         }
 
+        // File descriptor, from a path to a file to open for reading
+        (Value::String { val, .. } | Value::Glob { val, .. }, Some(DbusType::UnixFd)) => {
+            let path = nu_path::expand_path_with(val, fd_source.cwd, true);
+            let file = File::open(path).map_err(|err| {
+                LabeledError::new("Failed to open a file to send as a D-Bus `UnixFd`")
+                    .with_label(format!("{val}: {err}"), value.span())
+            })?;
+            // SAFETY: the fd was just taken from the file, so nothing else owns it
+            Ok(MessageItem::UnixFd(unsafe {
+                OwnedFd::from_raw_fd(file.into_raw_fd())
+            }))
+        }
+        // File descriptor, from a handle to one received earlier
+        (Value::Custom { .. }, Some(DbusType::UnixFd)) => {
+            let file = fd_source.fds.get(value)?.try_clone().map_err(|err| {
+                LabeledError::new("Failed to send a file descriptor")
+                    .with_label(err.to_string(), value.span())
+            })?;
+            // SAFETY: the fd is a new copy, so nothing else owns it
+            Ok(MessageItem::UnixFd(unsafe {
+                OwnedFd::from_raw_fd(file.into_raw_fd())
+            }))
+        }
+
         // Binary
         (Value::Binary { val, .. }, Some(r#type @ DbusType::Array(content_type)))
             if matches!(**content_type, DbusType::Byte) =>
@@ -197,7 +267,7 @@ pub fn to_message_item(
             let sig = Signature::from(r#type.stringify());
             let items = vals
                 .iter()
-                .map(|content| to_message_item(content, Some(content_type)))
+                .map(|content| to_message_item(content, Some(content_type), fd_source))
                 .collect::<Result<Vec<MessageItem>, _>>()?;
             Ok(MessageItem::Array(
                 MessageItemArray::new(items, sig).unwrap(),
@@ -220,7 +290,7 @@ pub fn to_message_item(
             let items = vals
                 .iter()
                 .zip(types)
-                .map(|(content, r#type)| to_message_item(content, Some(r#type)))
+                .map(|(content, r#type)| to_message_item(content, Some(r#type), fd_source))
                 .collect::<Result<Vec<MessageItem>, _>>()?;
             Ok(MessageItem::Struct(items))
         }
@@ -236,8 +306,9 @@ pub fn to_message_item(
                     .iter()
                     .map(|(key, val)| {
                         let key_as_value = Value::string(key, value.span());
-                        let key_message_item = to_message_item(&key_as_value, Some(key_type))?;
-                        let val_message_item = to_message_item(val, Some(val_type))?;
+                        let key_message_item =
+                            to_message_item(&key_as_value, Some(key_type), fd_source)?;
+                        let val_message_item = to_message_item(val, Some(val_type), fd_source)?;
                         Ok((key_message_item, val_message_item))
                     })
                     .collect::<Result<Vec<_>, LabeledError>>()?;
@@ -251,7 +322,7 @@ pub fn to_message_item(
 
         // Variant - use automatic type
         (other_value, Some(DbusType::Variant)) => Ok(MessageItem::Variant(Box::new(
-            to_message_item(other_value, None)?,
+            to_message_item(other_value, None, fd_source)?,
         ))),
 
         // Value not compatible with expected type
@@ -266,19 +337,23 @@ pub fn to_message_item(
         )),
 
         // Automatic types (with no type expectation)
-        (Value::String { .. }, None) => to_message_item(value, Some(&DbusType::String)),
-        (Value::Int { .. }, None) => to_message_item(value, Some(&DbusType::Int64)),
-        (Value::Float { .. }, None) => to_message_item(value, Some(&DbusType::Double)),
-        (Value::Bool { .. }, None) => to_message_item(value, Some(&DbusType::Boolean)),
-        (Value::List { .. }, None) => {
-            to_message_item(value, Some(&DbusType::Array(DbusType::Variant.into())))
-        }
+        (Value::String { .. }, None) => to_message_item(value, Some(&DbusType::String), fd_source),
+        (Value::Int { .. }, None) => to_message_item(value, Some(&DbusType::Int64), fd_source),
+        (Value::Float { .. }, None) => to_message_item(value, Some(&DbusType::Double), fd_source),
+        (Value::Bool { .. }, None) => to_message_item(value, Some(&DbusType::Boolean), fd_source),
+        (Value::List { .. }, None) => to_message_item(
+            value,
+            Some(&DbusType::Array(DbusType::Variant.into())),
+            fd_source,
+        ),
         (Value::Record { .. }, None) => to_message_item(
             value,
             Some(&DbusType::Array(
                 DbusType::DictEntry(DbusType::String.into(), DbusType::Variant.into()).into(),
             )),
+            fd_source,
         ),
+        (Value::Custom { .. }, None) => to_message_item(value, Some(&DbusType::UnixFd), fd_source),
 
         // No expected type, but can't handle this type
         _ => Err(LabeledError::new(format!(
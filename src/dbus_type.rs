@@ -16,6 +16,7 @@ pub enum DbusType {
     String,
     ObjectPath,
     Signature,
+    UnixFd,
     Array(Box<DbusType>),
     Struct(Vec<DbusType>),
     Variant,
@@ -44,6 +45,7 @@ impl DbusType {
             's' => Ok((String, &input[1..])),
             'o' => Ok((ObjectPath, &input[1..])),
             'g' => Ok((Signature, &input[1..])),
+            'h' => Ok((UnixFd, &input[1..])),
             'a' => {
                 // The next type is the content type of the array
                 let (content_type, remainder) = Self::parse(&input[1..])?;
@@ -120,6 +122,7 @@ impl DbusType {
             String => 's'.into(),
             ObjectPath => 'o'.into(),
             Signature => 'g'.into(),
+            UnixFd => 'h'.into(),
 
             // a<type>
             Array(content) => format!("a{}", content.stringify()),
@@ -160,6 +163,7 @@ fn test_parse_simple_types() {
     should_parse_to!("s", String);
     should_parse_to!("o", ObjectPath);
     should_parse_to!("g", Signature);
+    should_parse_to!("h", UnixFd);
     should_parse_to!("v", Variant);
 }
 
@@ -304,6 +308,7 @@ fn test_stringify_simple_types() {
     should_stringify_to!(String, "s");
     should_stringify_to!(ObjectPath, "o");
     should_stringify_to!(Signature, "g");
+    should_stringify_to!(UnixFd, "h");
     should_stringify_to!(Variant, "v");
 }
 
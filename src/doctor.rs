@@ -0,0 +1,454 @@
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use dbus::{arg::Variant, Message};
+use nu_protocol::{record, Span, Spanned, Value};
+
+use crate::{
+    address::DbusAddress,
+    auth::{self, current_uid, AuthMechanism},
+    config::DbusBusChoice,
+    connection::{session_bus_candidates, DbusConnection},
+    introspection::Node,
+    relay,
+};
+
+/// The outcome of a diagnostic check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+/// One diagnostic check, with a hint on how to fix it if it didn't pass
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn with_hint(mut self, hint: impl Into<String>) -> Check {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn to_value(&self, bus: &str, span: Span) -> Value {
+        Value::record(
+            record! {
+                "bus" => Value::string(bus, span),
+                "check" => Value::string(self.name, span),
+                "status" => Value::string(self.status.name(), span),
+                "detail" => Value::string(&self.detail, span),
+                "hint" => self
+                    .hint
+                    .as_ref()
+                    .map(|hint| Value::string(hint, span))
+                    .unwrap_or_default(),
+            },
+            span,
+        )
+    }
+}
+
+/// Check each step of connecting to a bus, stopping short of the steps that can't work because of
+/// an earlier failure
+pub fn diagnose(
+    bus_choice: &Spanned<DbusBusChoice>,
+    auth: Option<&Spanned<Vec<AuthMechanism>>>,
+    timeout: Duration,
+) -> Vec<Check> {
+    let mut checks = vec![check_environment(&bus_choice.item)];
+
+    let addresses = match find_addresses(&bus_choice.item) {
+        Ok(addresses) => {
+            checks.push(Check::new(
+                "address",
+                Status::Pass,
+                addresses
+                    .iter()
+                    .map(|address| address.to_string())
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ));
+            addresses
+        }
+        Err(check) => {
+            checks.push(check);
+            vec![]
+        }
+    };
+
+    // The socket and authentication checks are only done for the first address that can be
+    // connected to, which is the one that libdbus will most likely use
+    match addresses
+        .iter()
+        .find(|address| address.check_connectable().is_ok())
+    {
+        Some(address) => {
+            checks.push(check_socket(address));
+            let mechanisms = auth
+                .map(|auth| &auth.item[..])
+                .unwrap_or(&AuthMechanism::ALL);
+            checks.push(check_auth(address, mechanisms));
+        }
+        None => {
+            checks.push(Check::new("socket", Status::Skip, "no usable address"));
+            checks.push(Check::new(
+                "authentication",
+                Status::Skip,
+                "no usable address",
+            ));
+        }
+    }
+
+    let is_bus = !matches!(bus_choice.item, DbusBusChoice::Peer(_));
+    let conn = match DbusConnection::open(bus_choice, auth) {
+        Ok(conn) => {
+            let detail = match conn.unique_name() {
+                Some(name) => format!("connected with unique name {name}"),
+                None => "connected".into(),
+            };
+            checks.push(Check::new(
+                if is_bus { "hello" } else { "connect" },
+                Status::Pass,
+                detail,
+            ));
+            Some(conn)
+        }
+        Err(err) => {
+            let hint = err.help.clone().unwrap_or_else(|| {
+                "check the address, and that the server is running and accepts this user".into()
+            });
+            checks.push(
+                Check::new(
+                    if is_bus { "hello" } else { "connect" },
+                    Status::Fail,
+                    err.msg,
+                )
+                .with_hint(hint),
+            );
+            None
+        }
+    };
+
+    let Some(conn) = conn else {
+        for name in ["ping", "features", "monitoring"] {
+            checks.push(Check::new(name, Status::Skip, "not connected"));
+        }
+        return checks;
+    };
+
+    checks.push(check_ping(&conn, is_bus, timeout));
+    if is_bus {
+        checks.push(check_features(&conn, timeout));
+        checks.push(check_monitoring(&conn, timeout));
+    } else {
+        for name in ["features", "monitoring"] {
+            checks.push(Check::new(name, Status::Skip, "not a message bus"));
+        }
+    }
+    checks
+}
+
+fn check_environment(bus_choice: &DbusBusChoice) -> Check {
+    let var = |name: &str| std::env::var(name).ok();
+    match bus_choice {
+        DbusBusChoice::Session => match var("DBUS_SESSION_BUS_ADDRESS") {
+            Some(address) => Check::new(
+                "environment",
+                Status::Pass,
+                format!("DBUS_SESSION_BUS_ADDRESS={address}"),
+            ),
+            None => Check::new(
+                "environment",
+                Status::Warn,
+                "DBUS_SESSION_BUS_ADDRESS is not set, so the bus has to be looked for",
+            )
+            .with_hint(
+                "this is normal in cron jobs, services and some SSH sessions. Set it to the \
+                 output of `systemctl --user show-environment` or similar if the bus can't be found",
+            ),
+        },
+        DbusBusChoice::System => match var("DBUS_SYSTEM_BUS_ADDRESS") {
+            Some(address) => Check::new(
+                "environment",
+                Status::Pass,
+                format!("DBUS_SYSTEM_BUS_ADDRESS={address}"),
+            ),
+            None => Check::new(
+                "environment",
+                Status::Pass,
+                "DBUS_SYSTEM_BUS_ADDRESS is not set, so the default address is used",
+            ),
+        },
+        DbusBusChoice::Started => match var("DBUS_STARTER_ADDRESS") {
+            Some(address) => Check::new(
+                "environment",
+                Status::Pass,
+                format!("DBUS_STARTER_ADDRESS={address}"),
+            ),
+            None => Check::new(
+                "environment",
+                Status::Fail,
+                "DBUS_STARTER_ADDRESS is not set",
+            )
+            .with_hint("it's only set for programs started by a bus through activation"),
+        },
+        DbusBusChoice::User(_) => Check::new(
+            "environment",
+            Status::Skip,
+            "the bus of another user is looked for in the usual places",
+        ),
+        _ => Check::new("environment", Status::Skip, "the address was given directly"),
+    }
+}
+
+/// Find the addresses that would be tried for the bus choice
+fn find_addresses(bus_choice: &DbusBusChoice) -> Result<Vec<DbusAddress>, Check> {
+    let address = match bus_choice {
+        DbusBusChoice::Session if std::env::var_os("DBUS_SESSION_BUS_ADDRESS").is_none() => {
+            return Ok(session_bus_candidates(current_uid()));
+        }
+        DbusBusChoice::User(uid) => return Ok(session_bus_candidates(*uid)),
+        DbusBusChoice::Connection(_) => {
+            return Err(Check::new(
+                "address",
+                Status::Skip,
+                "connections from `dbus connect` are already open",
+            ))
+        }
+        other => other.address().unwrap_or_default(),
+    };
+    DbusAddress::parse_list(&address).map_err(|err| {
+        Check::new(
+            "address",
+            Status::Fail,
+            format!("{address:?} is invalid: {}", err.message),
+        )
+        .with_hint("use `dbus address parse` to see what's wrong with it")
+    })
+}
+
+fn check_socket(address: &DbusAddress) -> Check {
+    let Some(path) = address.get("path").filter(|_| address.transport == "unix") else {
+        return Check::new(
+            "socket",
+            Status::Skip,
+            format!("{address} is not a unix socket path"),
+        );
+    };
+    let path = Path::new(path);
+    match path.metadata() {
+        Err(err) => Check::new("socket", Status::Fail, format!("{}: {err}", path.display()))
+            .with_hint(
+                "the bus doesn't seem to be running. For a session bus, the user may not be \
+                 logged in (see `loginctl enable-linger`)",
+            ),
+        Ok(meta) if !meta.file_type().is_socket() => Check::new(
+            "socket",
+            Status::Fail,
+            format!("{} is not a socket", path.display()),
+        ),
+        Ok(_) if !can_write(path) => Check::new(
+            "socket",
+            Status::Fail,
+            format!("{} can't be written to by this user", path.display()),
+        )
+        .with_hint("connect as a user that has access, or use --user to pick the right bus"),
+        Ok(_) => Check::new(
+            "socket",
+            Status::Pass,
+            format!("{} exists and is writable", path.display()),
+        ),
+    }
+}
+
+fn can_write(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: the path is a valid nul-terminated string
+    unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) == 0 }
+}
+
+fn check_auth(address: &DbusAddress, mechanisms: &[AuthMechanism]) -> Check {
+    let relay::Transport {
+        mut reader,
+        mut writer,
+        close,
+    } = match relay::connect(address) {
+        Ok(transport) => transport,
+        Err(err) => {
+            return Check::new(
+                "authentication",
+                Status::Fail,
+                format!("can't connect: {err}"),
+            )
+        }
+    };
+    let result = auth::client_handshake(&mut reader, &mut writer, mechanisms, None);
+    drop(writer);
+    close();
+
+    match result {
+        Ok((mechanism, guid)) => Check::new(
+            "authentication",
+            Status::Pass,
+            format!("accepted {} by server {guid}", mechanism.name()),
+        ),
+        Err(err) => Check::new("authentication", Status::Fail, err.to_string()).with_hint(
+            "the server doesn't accept this user. If it offers other mechanisms, try them \
+             with --auth",
+        ),
+    }
+}
+
+fn bus_method_call(interface: &str, method: &str) -> Message {
+    Message::new_method_call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        interface,
+        method,
+    )
+    .expect("valid method call")
+}
+
+fn check_ping(conn: &DbusConnection, is_bus: bool, timeout: Duration) -> Check {
+    let mut message = bus_method_call("org.freedesktop.DBus.Peer", "Ping");
+    if !is_bus {
+        // A peer answers for itself
+        message.set_destination(None);
+    }
+    let start = Instant::now();
+    match conn.channel().send_with_reply_and_block(message, timeout) {
+        Ok(_) => Check::new(
+            "ping",
+            Status::Pass,
+            format!("replied in {:?}", start.elapsed()),
+        ),
+        Err(err) => Check::new(
+            "ping",
+            Status::Fail,
+            err.message().unwrap_or("no reply").to_owned(),
+        )
+        .with_hint("the server is connected but not responding; try a longer --timeout"),
+    }
+}
+
+fn check_features(conn: &DbusConnection, timeout: Duration) -> Check {
+    let message = bus_method_call("org.freedesktop.DBus.Properties", "Get")
+        .append2("org.freedesktop.DBus", "Features");
+    match conn
+        .channel()
+        .send_with_reply_and_block(message, timeout)
+        .map(|reply| reply.get1::<Variant<Vec<String>>>())
+    {
+        Ok(Some(Variant(features))) if features.is_empty() => {
+            Check::new("features", Status::Pass, "no optional features")
+        }
+        Ok(Some(Variant(features))) => Check::new("features", Status::Pass, features.join(", ")),
+        Ok(None) => Check::new("features", Status::Warn, "unexpected reply"),
+        Err(err) => Check::new(
+            "features",
+            Status::Warn,
+            format!(
+                "the bus doesn't report its features: {}",
+                err.message().unwrap_or_default()
+            ),
+        )
+        .with_hint("this is normal for older or minimal bus implementations"),
+    }
+}
+
+fn check_monitoring(conn: &DbusConnection, timeout: Duration) -> Check {
+    let message = bus_method_call("org.freedesktop.DBus.Introspectable", "Introspect");
+    let node = conn
+        .channel()
+        .send_with_reply_and_block(message, timeout)
+        .ok()
+        .and_then(|reply| reply.get1::<String>())
+        .and_then(|xml| Node::from_xml(&xml).ok());
+    match node {
+        Some(node)
+            if node
+                .get_interface("org.freedesktop.DBus.Monitoring")
+                .is_some() =>
+        {
+            Check::new("monitoring", Status::Pass, "BecomeMonitor is supported")
+        }
+        Some(_) => Check::new(
+            "monitoring",
+            Status::Warn,
+            "the bus doesn't support BecomeMonitor",
+        )
+        .with_hint("monitoring may still be possible by eavesdropping, if the bus policy allows"),
+        None => Check::new(
+            "monitoring",
+            Status::Warn,
+            "the bus can't be introspected to find out",
+        ),
+    }
+}
+
+#[test]
+fn test_diagnose_test_bus() {
+    let bus = crate::test_bus::TestBus::start().unwrap();
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus(bus.address().into()),
+        span: Span::test_data(),
+    };
+    let checks = diagnose(&bus_choice, None, Duration::from_secs(5));
+    let status = |name: &str| {
+        checks
+            .iter()
+            .find(|check| check.name == name)
+            .map(|check| check.status)
+    };
+    assert_eq!(status("environment"), Some(Status::Skip));
+    for name in ["address", "socket", "authentication", "hello", "ping"] {
+        assert_eq!(status(name), Some(Status::Pass), "{name}: {checks:?}");
+    }
+}
+
+#[test]
+fn test_diagnose_missing_socket() {
+    let bus_choice = Spanned {
+        item: DbusBusChoice::Bus("unix:path=/nonexistent/nu_plugin_dbus".into()),
+        span: Span::test_data(),
+    };
+    let checks = diagnose(&bus_choice, None, Duration::from_secs(5));
+    let socket = checks.iter().find(|check| check.name == "socket").unwrap();
+    assert_eq!(socket.status, Status::Fail);
+    assert!(socket.hint.is_some());
+    let ping = checks.iter().find(|check| check.name == "ping").unwrap();
+    assert_eq!(ping.status, Status::Skip);
+}
@@ -0,0 +1,223 @@
+use std::{fs::File, os::fd::AsRawFd, sync::Arc};
+
+use nu_plugin::EngineInterface;
+use nu_protocol::{LabeledError, Span, Value};
+
+use crate::handle::{DbusHandle, Registry};
+
+/// File descriptors received in replies, kept open until their handles are closed or dropped
+#[derive(Clone, Default)]
+pub struct FdStore {
+    files: Arc<Registry<File>>,
+    /// Told to keep the plugin running while there are file descriptors open
+    engine: Option<EngineInterface>,
+}
+
+impl FdStore {
+    /// The same store, which tells the engine to keep the plugin running while it has file
+    /// descriptors open
+    pub fn with_engine(&self, engine: &EngineInterface) -> FdStore {
+        FdStore {
+            files: self.files.clone(),
+            engine: Some(engine.clone()),
+        }
+    }
+
+    /// Keep a file descriptor open, returning a handle that refers to it
+    pub fn keep(&self, file: File, span: Span) -> Value {
+        let fd = file.as_raw_fd();
+        let id = self.files.insert(file);
+        if let Some(engine) = &self.engine {
+            // The handle would be useless if the plugin was stopped
+            let _ = engine.set_gc_disabled(true);
+        }
+        DbusHandle::Fd { id, fd }.into_value(span)
+    }
+
+    /// Get the file descriptor that a handle refers to
+    pub fn get(&self, value: &Value) -> Result<Arc<File>, LabeledError> {
+        self.files
+            .get(handle_id(value)?)
+            .ok_or_else(|| closed(value.span()))
+    }
+
+    /// Close the file descriptor that a handle refers to
+    ///
+    /// It stays open until anything still reading or writing it is done.
+    pub fn close(&self, value: &Value) -> Result<(), LabeledError> {
+        self.files
+            .remove(handle_id(value)?)
+            .map(drop)
+            .ok_or_else(|| closed(value.span()))
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.files.remove(id);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+fn closed(span: Span) -> LabeledError {
+    LabeledError::new("This file descriptor has been closed")
+        .with_label("file descriptor used here", span)
+}
+
+fn handle_id(value: &Value) -> Result<u64, LabeledError> {
+    match value
+        .as_custom_value()
+        .ok()
+        .and_then(|custom| custom.as_any().downcast_ref::<DbusHandle>())
+    {
+        Some(DbusHandle::Fd { id, .. }) => Ok(*id),
+        _ => Err(LabeledError::new("Expected a D-Bus file descriptor")
+            .with_label("this should come from a D-Bus reply", value.span())),
+    }
+}
+
+#[test]
+fn test_fd_store() {
+    use std::io::{Read, Write};
+
+    let store = FdStore::default();
+    let (mut reader, writer) = std::io::pipe().unwrap();
+    let handle = store.keep(
+        File::from(std::os::fd::OwnedFd::from(writer)),
+        Span::test_data(),
+    );
+
+    (&*store.get(&handle).unwrap()).write_all(b"hello").unwrap();
+    // Closing the write end lets the reader see the end of the file
+    store.close(&handle).unwrap();
+    let mut read = String::new();
+    reader.read_to_string(&mut read).unwrap();
+    assert_eq!(read, "hello");
+
+    assert_eq!(
+        store.close(&handle).unwrap_err().msg,
+        "This file descriptor has been closed"
+    );
+    assert!(store.is_empty());
+    assert!(store.get(&Value::test_int(3)).is_err());
+}
+
+#[test]
+fn test_fds_from_message() {
+    use std::io::{Read, Write};
+
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let message = dbus::Message::new_method_call("org.test", "/", "org.test", "Open")
+        .unwrap()
+        .append1(File::from(std::os::fd::OwnedFd::from(reader)));
+    writer.write_all(b"hello").unwrap();
+    drop(writer);
+
+    let store = FdStore::default();
+    let span = Span::test_data();
+    let args =
+        crate::convert::from_message(&message, span, Default::default(), Some(&store)).unwrap();
+    // Still readable after the message is gone
+    drop(message);
+    let mut read = String::new();
+    (&*store.get(&args[0]).unwrap())
+        .read_to_string(&mut read)
+        .unwrap();
+    assert_eq!(read, "hello");
+
+    // Without a store, there's nothing to refer to
+    let message = dbus::Message::new_method_call("org.test", "/", "org.test", "Open")
+        .unwrap()
+        .append1(File::open("/dev/null").unwrap());
+    let args = crate::convert::from_message(&message, span, Default::default(), None).unwrap();
+    assert_eq!(args, vec![Value::test_string("<unix fd>")]);
+}
+
+#[test]
+fn test_send_fd() {
+    use std::io::{Read, Write};
+
+    use crate::client::{bus_call, next_call, spanned, DbusClient, TestClient, FD_PASSING_HELP};
+
+    let test = TestClient::start(|config| {
+        config.introspect = false;
+        config.cwd = env!("CARGO_MANIFEST_DIR").into();
+    });
+
+    // A service that sends back the file descriptors it's given
+    let (service_name, service) = test.serve(|service| {
+        for _ in 0..2 {
+            let call = next_call(&service);
+            let file: File = call.read1().unwrap();
+            service.send(call.method_return().append1(file)).unwrap();
+            service.flush();
+        }
+    });
+    let store = FdStore::default();
+    let client = test.client().with_fds(store.clone());
+    let echo = |value: Value| {
+        let reply = client
+            .call(
+                &spanned(&service_name),
+                &spanned("/"),
+                &spanned("com.example.Echo"),
+                &spanned("Echo"),
+                Some(&spanned("h")),
+                &[value],
+            )
+            .unwrap();
+        store.get(&reply[0]).unwrap()
+    };
+
+    // A pipe end, sent from a handle and read from the one that comes back
+    let (reader, mut writer) = std::io::pipe().unwrap();
+    let sent = store.keep(
+        File::from(std::os::fd::OwnedFd::from(reader)),
+        Span::test_data(),
+    );
+    let received = echo(sent.clone());
+    store.close(&sent).unwrap();
+    writer.write_all(b"hello").unwrap();
+    drop(writer);
+    let mut read = String::new();
+    (&*received).read_to_string(&mut read).unwrap();
+    assert_eq!(read, "hello");
+
+    // A file, opened from a path relative to the current directory
+    let received = echo(Value::test_string("Cargo.toml"));
+    let mut read = String::new();
+    (&*received).read_to_string(&mut read).unwrap();
+    assert!(read.starts_with("[package]"));
+    service.join().unwrap();
+
+    let err = bus_call(
+        &test.client(),
+        "org.freedesktop.DBus.Peer",
+        "Ping",
+        "h",
+        &[Value::test_string("does-not-exist")],
+    )
+    .unwrap_err();
+    assert_eq!(err.msg, "Failed to open a file to send as a D-Bus `UnixFd`");
+
+    // The auth relay can't pass file descriptors
+    let relayed = crate::connection::DbusConnection::open(
+        &test.config.bus_choice,
+        Some(&nu_protocol::Spanned {
+            item: vec![crate::auth::AuthMechanism::Anonymous],
+            span: Span::test_data(),
+        }),
+    )
+    .unwrap();
+    let err = bus_call(
+        &DbusClient::new(test.config.clone(), std::sync::Arc::new(relayed)),
+        "org.freedesktop.DBus.Peer",
+        "Ping",
+        "h",
+        &[Value::test_string("Cargo.toml")],
+    )
+    .unwrap_err();
+    assert!(err.msg.contains("file descriptors"), "{err:?}");
+    assert_eq!(err.help.as_deref(), Some(FD_PASSING_HELP));
+}
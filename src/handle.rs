@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use nu_protocol::{record, ShellError, Span, Value};
+use serde::{Deserialize, Serialize};
+
+/// Resources kept alive in the plugin on behalf of custom values in the engine
+pub struct Registry<T> {
+    next_id: AtomicU64,
+    items: Mutex<HashMap<u64, Arc<T>>>,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Registry {
+            next_id: AtomicU64::new(1),
+            items: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Arc<T>>> {
+        self.items.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Add an item, returning the id that refers to it
+    pub fn insert(&self, item: T) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(id, Arc::new(item));
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<T>> {
+        self.lock().get(&id).cloned()
+    }
+
+    pub fn remove(&self, id: u64) -> Option<Arc<T>> {
+        self.lock().remove(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+}
+
+/// Custom values referring to resources held by the plugin
+///
+/// The plugin is notified when the engine drops these, and then releases the resource.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DbusHandle {
+    /// An in-process bus started by `dbus test-bus`
+    TestBus { id: u64, address: String },
+    /// A connection opened by `dbus connect`
+    Connection {
+        id: u64,
+        unique_name: Option<String>,
+        address: String,
+    },
+    /// A method call started by `dbus call --async`
+    PendingCall {
+        id: u64,
+        dest: String,
+        method: String,
+    },
+    /// A file descriptor received in a reply
+    Fd { id: u64, fd: i32 },
+}
+
+impl DbusHandle {
+    pub fn into_value(self, span: Span) -> Value {
+        Value::custom(Box::new(self), span)
+    }
+
+    /// Represent the handle as a record, for display and cell paths
+    fn to_record(&self, span: Span) -> Value {
+        match self {
+            DbusHandle::TestBus { address, .. } => Value::record(
+                record! {
+                    "address" => Value::string(address, span),
+                },
+                span,
+            ),
+            DbusHandle::Connection {
+                unique_name,
+                address,
+                ..
+            } => Value::record(
+                record! {
+                    "unique_name" => unique_name
+                        .as_ref()
+                        .map(|name| Value::string(name, span))
+                        .unwrap_or_default(),
+                    "address" => Value::string(address, span),
+                },
+                span,
+            ),
+            DbusHandle::PendingCall { dest, method, .. } => Value::record(
+                record! {
+                    "dest" => Value::string(dest, span),
+                    "method" => Value::string(method, span),
+                },
+                span,
+            ),
+            DbusHandle::Fd { fd, .. } => Value::record(
+                record! {
+                    "fd" => Value::int(*fd as i64, span),
+                },
+                span,
+            ),
+        }
+    }
+}
+
+#[typetag::serde]
+impl nu_protocol::CustomValue for DbusHandle {
+    fn clone_value(&self, span: Span) -> Value {
+        self.clone().into_value(span)
+    }
+
+    fn type_name(&self) -> String {
+        match self {
+            DbusHandle::TestBus { .. } => "dbus test-bus".into(),
+            DbusHandle::Connection { .. } => "dbus connection".into(),
+            DbusHandle::PendingCall { .. } => "dbus pending-call".into(),
+            DbusHandle::Fd { .. } => "dbus fd".into(),
+        }
+    }
+
+    fn to_base_value(&self, span: Span) -> Result<Value, ShellError> {
+        Ok(self.to_record(span))
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_mut_any(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn follow_path_string(
+        &self,
+        self_span: Span,
+        column_name: String,
+        path_span: Span,
+        optional: bool,
+        casing: nu_protocol::casing::Casing,
+    ) -> Result<Value, ShellError> {
+        self.to_record(self_span)
+            .follow_cell_path(&[nu_protocol::ast::PathMember::String {
+                val: column_name,
+                span: path_span,
+                optional,
+                casing,
+            }])
+            .map(|value| value.into_owned())
+    }
+
+    fn notify_plugin_on_drop(&self) -> bool {
+        true
+    }
+}
@@ -48,6 +48,14 @@ impl Node {
         )
     }
 
+    /// Find a method on an interface on this node, and check whether it is annotated as never
+    /// replying
+    pub fn is_method_no_reply(&self, interface: &str, method: &str) -> bool {
+        self.get_interface(interface)
+            .and_then(|i| i.get_method(method))
+            .is_some_and(|m| m.is_no_reply())
+    }
+
     /// Find the signature of a property on an interface on this node
     pub fn get_property_signature(&self, interface: &str, property: &str) -> Option<&str> {
         Some(
@@ -134,6 +142,14 @@ impl Method {
             .collect()
     }
 
+    /// Whether the method is annotated with `org.freedesktop.DBus.Method.NoReply`, meaning that
+    /// it never sends a reply
+    pub fn is_no_reply(&self) -> bool {
+        self.annotations
+            .iter()
+            .any(|a| a.name == "org.freedesktop.DBus.Method.NoReply" && a.value == "true")
+    }
+
     #[allow(dead_code)]
     /// Get the signature of the method result
     pub fn out_signature(&self) -> String {
@@ -399,3 +415,17 @@ pub fn test_get_method_args_signature() {
         Some("ias".into())
     );
 }
+
+#[test]
+pub fn test_is_method_no_reply() {
+    let mut node = test_introspection_doc_rs();
+    assert!(!node.is_method_no_reply("com.example.SampleInterface0", "Mogrify"));
+    node.interfaces[0].methods[2]
+        .annotations
+        .push(Annotation::new(
+            "org.freedesktop.DBus.Method.NoReply",
+            "true",
+        ));
+    assert!(node.is_method_no_reply("com.example.SampleInterface0", "Mogrify"));
+    assert!(!node.is_method_no_reply("com.example.SampleInterface0", "Frobate"));
+}
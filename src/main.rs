@@ -1,20 +1,202 @@
-use nu_plugin::{serve_plugin, MsgPackSerializer, Plugin, PluginCommand};
-use nu_protocol::SyntaxShape;
+use std::{panic::AssertUnwindSafe, time::Duration};
 
+use nu_plugin::{serve_plugin, EngineInterface, MsgPackSerializer, Plugin, PluginCommand};
+use nu_protocol::{CustomValue, LabeledError, Signals, Span, SyntaxShape};
+
+use crate::{
+    client::DbusClient,
+    config::{DbusBusChoice, DbusClientConfig},
+    connection::{ConnectionPool, DbusConnection},
+    fd::FdStore,
+    handle::{DbusHandle, Registry},
+    pending::PendingCall,
+    test_bus::TestBus,
+};
+
+mod address;
+mod audit;
+mod auth;
+mod bench;
 mod client;
 mod commands;
 mod config;
+mod connection;
 mod convert;
 mod dbus_type;
+mod doctor;
+mod fd;
+mod handle;
 mod introspection;
 mod pattern;
+mod pending;
+mod redact;
+mod relay;
+mod test_bus;
 
 fn main() {
-    serve_plugin(&NuPluginDbus, MsgPackSerializer)
+    serve_plugin(&NuPluginDbus::default(), MsgPackSerializer)
 }
 
 /// The main plugin interface for nushell
-pub struct NuPluginDbus;
+#[derive(Default)]
+pub struct NuPluginDbus {
+    /// Connections reused between commands
+    pool: ConnectionPool,
+    /// Connections opened by `dbus connect`
+    connections: Registry<DbusConnection>,
+    /// Buses started by `dbus test-bus`
+    test_buses: Registry<TestBus>,
+    /// Calls started by `dbus call --async`
+    pending_calls: Registry<PendingCall>,
+    /// File descriptors received in replies
+    fds: FdStore,
+}
+
+impl NuPluginDbus {
+    /// Get a client for the configured bus, reusing a pooled connection if possible
+    pub fn client(
+        &self,
+        engine: &EngineInterface,
+        config: DbusClientConfig,
+    ) -> Result<DbusClient, LabeledError> {
+        let conn = match config.bus_choice.item {
+            DbusBusChoice::Connection(id) => self.connections.get(id).ok_or_else(|| {
+                LabeledError::new("This connection has been closed")
+                    .with_label("connection used here", config.bus_choice.span)
+            })?,
+            _ => self.pool.get(&config.bus_choice, config.auth.as_ref())?,
+        };
+        Ok(DbusClient::new(config, conn).with_fds(self.fds.with_engine(engine)))
+    }
+
+    /// Close pooled connections, either just the one for the bus choice or all of them
+    pub fn disconnect(&self, bus_choice: Option<&DbusBusChoice>) {
+        match bus_choice {
+            Some(bus_choice) => self.pool.remove(bus_choice),
+            None => self.pool.clear(),
+        }
+    }
+
+    /// Keep a dedicated connection open until the returned handle is dropped
+    pub fn add_connection(
+        &self,
+        engine: &EngineInterface,
+        conn: DbusConnection,
+    ) -> Result<DbusHandle, LabeledError> {
+        let unique_name = conn.unique_name().map(|name| name.to_owned());
+        let address = conn.address().to_owned();
+        let id = self.connections.insert(conn);
+        self.update_gc(engine)?;
+        Ok(DbusHandle::Connection {
+            id,
+            unique_name,
+            address,
+        })
+    }
+
+    /// Keep a test bus running until the returned handle is dropped
+    pub fn add_test_bus(
+        &self,
+        engine: &EngineInterface,
+        bus: TestBus,
+    ) -> Result<DbusHandle, LabeledError> {
+        let address = bus.address().to_owned();
+        let id = self.test_buses.insert(bus);
+        self.update_gc(engine)?;
+        Ok(DbusHandle::TestBus { id, address })
+    }
+
+    /// Run a method call in the background, returning a handle that `dbus await` can get its
+    /// result from. Waiting for it gives up a little after `time_limit`, if that's known.
+    pub fn add_pending_call(
+        &self,
+        engine: &EngineInterface,
+        dest: String,
+        method: String,
+        time_limit: Option<Duration>,
+        run: impl FnOnce() -> Result<nu_protocol::Value, LabeledError> + Send + 'static,
+    ) -> Result<DbusHandle, LabeledError> {
+        let id = self.pending_calls.insert(PendingCall::new(time_limit));
+        let pending = self.pending_calls.get(id).expect("just inserted");
+        std::thread::Builder::new()
+            .name("dbus async call".into())
+            .spawn(move || {
+                // Finish even if the call panics, so that waiting for it doesn't block forever
+                let result = std::panic::catch_unwind(AssertUnwindSafe(run))
+                    .unwrap_or_else(|_| Err(LabeledError::new("The call failed unexpectedly")));
+                pending.finish(result)
+            })
+            .map_err(|err| {
+                self.pending_calls.remove(id);
+                LabeledError::new(format!("Couldn't start the call: {err}"))
+            })?;
+        self.update_gc(engine)?;
+        Ok(DbusHandle::PendingCall { id, dest, method })
+    }
+
+    /// Wait for a call started by `dbus call --async` to finish
+    pub fn await_call(
+        &self,
+        handle: &DbusHandle,
+        signals: &Signals,
+        span: Span,
+    ) -> Option<Result<nu_protocol::Value, LabeledError>> {
+        match handle {
+            DbusHandle::PendingCall { id, .. } => {
+                Some(self.pending_calls.get(*id)?.wait(signals, span))
+            }
+            _ => None,
+        }
+    }
+
+    /// The file descriptors received in replies, for reading and writing through their handles
+    pub fn fds(&self) -> &FdStore {
+        &self.fds
+    }
+
+    /// Close a file descriptor received in a reply, before its handle is dropped
+    pub fn close_fd(
+        &self,
+        engine: &EngineInterface,
+        handle: &nu_protocol::Value,
+    ) -> Result<(), LabeledError> {
+        self.fds.close(handle)?;
+        self.update_gc(engine)
+    }
+
+    /// The plugin must not be stopped while it has resources in use by handles
+    fn update_gc(&self, engine: &EngineInterface) -> Result<(), LabeledError> {
+        let in_use = !self.connections.is_empty()
+            || !self.test_buses.is_empty()
+            || !self.pending_calls.is_empty()
+            || !self.fds.is_empty();
+        engine.set_gc_disabled(in_use)?;
+        Ok(())
+    }
+
+    fn drop_handle(
+        &self,
+        engine: &EngineInterface,
+        handle: &DbusHandle,
+    ) -> Result<(), LabeledError> {
+        match handle {
+            DbusHandle::TestBus { id, .. } => {
+                self.test_buses.remove(*id);
+            }
+            DbusHandle::Connection { id, .. } => {
+                self.connections.remove(*id);
+            }
+            DbusHandle::PendingCall { id, .. } => {
+                // The call carries on, but nobody will get the result
+                self.pending_calls.remove(*id);
+            }
+            DbusHandle::Fd { id, .. } => {
+                self.fds.remove(*id);
+            }
+        }
+        self.update_gc(engine)
+    }
+}
 
 impl Plugin for NuPluginDbus {
     fn version(&self) -> String {
@@ -24,14 +206,38 @@ impl Plugin for NuPluginDbus {
     fn commands(&self) -> Vec<Box<dyn PluginCommand<Plugin = Self>>> {
         vec![
             Box::new(commands::Main),
+            Box::new(commands::AddressParse),
             Box::new(commands::Introspect),
             Box::new(commands::Call),
+            Box::new(commands::Await),
+            Box::new(commands::Batch),
+            Box::new(commands::Bench),
+            Box::new(commands::Connect),
             Box::new(commands::Get),
             Box::new(commands::GetAll),
             Box::new(commands::Set),
+            Box::new(commands::StartService),
+            Box::new(commands::Disconnect),
+            Box::new(commands::Doctor),
+            Box::new(commands::FdRead),
+            Box::new(commands::FdWrite),
+            Box::new(commands::FdClose),
             Box::new(commands::List),
+            Box::new(commands::TestBus),
+            Box::new(commands::Whoami),
         ]
     }
+
+    fn custom_value_dropped(
+        &self,
+        engine: &EngineInterface,
+        custom_value: Box<dyn CustomValue>,
+    ) -> Result<(), LabeledError> {
+        if let Some(handle) = custom_value.as_any().downcast_ref::<DbusHandle>() {
+            self.drop_handle(engine, handle)?;
+        }
+        Ok(())
+    }
 }
 
 /// For conveniently adding the base options to a dbus command
@@ -39,6 +245,11 @@ trait DbusSignatureUtilExt {
     fn dbus_command(self) -> Self;
     fn accepts_dbus_client_options(self) -> Self;
     fn accepts_timeout(self) -> Self;
+    fn accepts_all_matching(self) -> Self;
+    fn accepts_retry(self) -> Self;
+    fn accepts_no_auto_start(self) -> Self;
+    fn accepts_interactive_auth(self) -> Self;
+    fn accepts_unsafe(self) -> Self;
 }
 
 impl DbusSignatureUtilExt for nu_protocol::Signature {
@@ -54,6 +265,12 @@ impl DbusSignatureUtilExt for nu_protocol::Signature {
                 "Send to the bus that started this process, if applicable",
                 None,
             )
+            .named(
+                "user",
+                SyntaxShape::Int,
+                "Send to the session bus of the user with this uid",
+                None,
+            )
             .named(
                 "bus",
                 SyntaxShape::String,
@@ -67,6 +284,20 @@ impl DbusSignatureUtilExt for nu_protocol::Signature {
                  Will not call the Hello method on initialization.",
                 None,
             )
+            .named(
+                "auth",
+                SyntaxShape::Any,
+                "How to authenticate to --bus or --peer: EXTERNAL, DBUS_COOKIE_SHA1, ANONYMOUS, \
+                 or a list of these to try in order",
+                None,
+            )
+            .named(
+                "conn",
+                SyntaxShape::Any,
+                "Use a connection opened by `dbus connect`, keeping the same unique name \
+                 across commands",
+                None,
+            )
     }
 
     fn accepts_timeout(self) -> Self {
@@ -77,4 +308,56 @@ impl DbusSignatureUtilExt for nu_protocol::Signature {
             None,
         )
     }
+
+    fn accepts_retry(self) -> Self {
+        self.named(
+            "wait-for-name",
+            SyntaxShape::Duration,
+            "Wait up to this long for the destination to appear on the bus before sending to it",
+            None,
+        )
+        .named(
+            "retries",
+            SyntaxShape::Int,
+            "How many more times to try if the call fails with a retryable error, such as \
+             ServiceUnknown (see the retry plugin setting)",
+            None,
+        )
+    }
+
+    fn accepts_no_auto_start(self) -> Self {
+        self.switch(
+            "no-auto-start",
+            "Don't let the bus start the destination if it isn't running",
+            None,
+        )
+    }
+
+    fn accepts_interactive_auth(self) -> Self {
+        self.switch(
+            "interactive-auth",
+            "Allow the service to ask the user to authorize the call, e.g. with a polkit \
+             password prompt",
+            None,
+        )
+    }
+
+    fn accepts_unsafe(self) -> Self {
+        self.switch(
+            "unsafe",
+            "Allow methods that change something in read-only mode (see the safety plugin \
+             setting). Methods on the deny list are still refused",
+            None,
+        )
+    }
+
+    fn accepts_all_matching(self) -> Self {
+        self.named(
+            "all-matching",
+            SyntaxShape::String,
+            "Instead of --dest, send to every name that matches this glob-like pattern \
+             (as in `dbus list`), returning a table of results",
+            None,
+        )
+    }
 }
@@ -0,0 +1,95 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use nu_protocol::{LabeledError, Signals, Span, Value};
+
+/// How much longer than its time limit to wait for a call before giving up on it
+const MARGIN: Duration = Duration::from_secs(1);
+
+/// How often to check for Ctrl-C while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A method call running in the background, started by `dbus call --async`
+pub struct PendingCall {
+    result: Mutex<Option<Result<Value, LabeledError>>>,
+    done: Condvar,
+    /// When to stop waiting, if it's known how long the call can take
+    deadline: Option<Instant>,
+}
+
+impl PendingCall {
+    /// A call that will be finished within `time_limit`, if that's known
+    pub fn new(time_limit: Option<Duration>) -> PendingCall {
+        PendingCall {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+            deadline: time_limit.map(|limit| Instant::now() + limit + MARGIN),
+        }
+    }
+
+    /// Store the result of the call, waking up anyone waiting for it
+    pub fn finish(&self, result: Result<Value, LabeledError>) {
+        *self.lock() = Some(result);
+        self.done.notify_all();
+    }
+
+    /// Wait for the call to finish and get its result
+    ///
+    /// This gives up on an interrupt, or if the call is taking longer than its time limit. The
+    /// result can be retrieved more than once.
+    pub fn wait(&self, signals: &Signals, span: Span) -> Result<Value, LabeledError> {
+        let mut result = self.lock();
+        loop {
+            if let Some(result) = &*result {
+                return result.clone();
+            }
+            signals.check(&span)?;
+            let mut timeout = POLL_INTERVAL;
+            if let Some(deadline) = self.deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(LabeledError::new("The call didn't finish in time")
+                        .with_label("waited longer than its timeout allows", span));
+                }
+                timeout = timeout.min(remaining);
+            }
+            result = self
+                .done
+                .wait_timeout(result, timeout)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Result<Value, LabeledError>>> {
+        self.result.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[test]
+fn test_pending_call_wait() {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    let pending = Arc::new(PendingCall::new(Some(Duration::from_secs(5))));
+    let finisher = pending.clone();
+    let thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        finisher.finish(Ok(Value::test_int(42)));
+    });
+    let signals = Signals::empty();
+    let span = Span::test_data();
+    assert_eq!(pending.wait(&signals, span).unwrap(), Value::test_int(42));
+    // Again, after it's done
+    assert_eq!(pending.wait(&signals, span).unwrap(), Value::test_int(42));
+    thread.join().unwrap();
+
+    // A call that never finishes
+    let pending = PendingCall::new(Some(Duration::ZERO));
+    let err = pending.wait(&signals, span).unwrap_err();
+    assert_eq!(err.msg, "The call didn't finish in time");
+    let pending = PendingCall::new(None);
+    let signals = Signals::new(Arc::new(AtomicBool::new(true)));
+    assert!(pending.wait(&signals, span).is_err());
+}
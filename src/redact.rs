@@ -0,0 +1,257 @@
+use nu_protocol::{LabeledError, Value};
+
+use crate::pattern::Pattern;
+
+/// What sensitive values are replaced with
+pub const REDACTED: &str = "<redacted>";
+
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+/// Where sensitive values are found in method calls, so that they can be kept out of the audit log
+/// and error messages. They are still sent as they are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Redaction {
+    pub rules: Vec<RedactionRule>,
+}
+
+/// A kind of sensitive value
+///
+/// If neither `arg` nor `key` is given, all arguments and return values are sensitive. For a
+/// property, the value is sensitive, or just the values under `key` in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionRule {
+    /// Pattern for the interface name
+    pub interface: String,
+    /// Pattern for the method or property name
+    pub member: String,
+    /// The index of the sensitive argument to the method
+    pub arg: Option<usize>,
+    /// Pattern for the keys of dictionaries (at any depth) that have sensitive values
+    pub key: Option<String>,
+}
+
+impl RedactionRule {
+    fn matches(&self, interface: &str, member: &str) -> bool {
+        Pattern::new(&self.interface, Some('.')).is_match(interface)
+            && Pattern::new(&self.member, None).is_match(member)
+    }
+
+    /// Redact the sensitive parts of a value, which is the whole value unless there is a key
+    fn apply(&self, value: &mut Value) {
+        match &self.key {
+            Some(key) => redact_keys(value, &Pattern::new(key, None)),
+            None => *value = Value::string(REDACTED, value.span()),
+        }
+    }
+}
+
+impl Redaction {
+    fn matching<'a>(
+        &'a self,
+        interface: &'a str,
+        member: &'a str,
+    ) -> impl Iterator<Item = &'a RedactionRule> + 'a {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.matches(interface, member))
+    }
+
+    /// Redact the arguments of a method call, including the value given to `Properties.Set`
+    pub fn redact_args(&self, interface: &str, member: &str, args: &mut [Value]) {
+        if interface == PROPERTIES && member == "Set" {
+            if let [iface, property, value] = args {
+                if let (Ok(iface), Ok(property)) = (iface.as_str(), property.as_str()) {
+                    self.matching(iface, property)
+                        .for_each(|rule| rule.apply(value));
+                }
+            }
+            return;
+        }
+        for rule in self.matching(interface, member) {
+            match rule.arg {
+                Some(index) => {
+                    if let Some(arg) = args.get_mut(index) {
+                        rule.apply(arg);
+                    }
+                }
+                None => args.iter_mut().for_each(|arg| rule.apply(arg)),
+            }
+        }
+    }
+
+    /// Redact the return values of a method call, including the values from `Properties.Get`
+    /// and `Properties.GetAll`
+    ///
+    /// Only rules without an argument index apply to return values.
+    pub fn redact_result(&self, interface: &str, member: &str, args: &[Value], result: &mut Value) {
+        match (interface, member, args) {
+            (PROPERTIES, "Get", [iface, property]) => {
+                if let (Ok(iface), Ok(property)) = (iface.as_str(), property.as_str()) {
+                    self.matching(iface, property)
+                        .for_each(|rule| rule.apply(result));
+                }
+            }
+            (PROPERTIES, "GetAll", [iface]) => {
+                let Ok(iface) = iface.as_str() else {
+                    return;
+                };
+                // The result is a list with the record of properties in it
+                let properties = match result {
+                    Value::List { vals, .. } => vals.first_mut(),
+                    other => Some(other),
+                };
+                if let Some(Value::Record { val, .. }) = properties {
+                    for (property, value) in val.to_mut().iter_mut() {
+                        self.matching(iface, property)
+                            .for_each(|rule| rule.apply(value));
+                    }
+                }
+            }
+            _ => self
+                .matching(interface, member)
+                .filter(|rule| rule.arg.is_none())
+                .for_each(|rule| rule.apply(result)),
+        }
+    }
+
+    /// Hide what an error for converting one of the arguments of a method call says, if the
+    /// argument is sensitive
+    pub fn redact_arg_error(
+        &self,
+        interface: &str,
+        member: &str,
+        args: &[Value],
+        index: usize,
+        mut err: LabeledError,
+    ) -> LabeledError {
+        let mut redacted = args.to_vec();
+        self.redact_args(interface, member, &mut redacted);
+        if redacted.get(index) != args.get(index) {
+            for label in err.labels.iter_mut() {
+                label.text = REDACTED.into();
+            }
+        }
+        err
+    }
+}
+
+fn redact_keys(value: &mut Value, key: &Pattern) {
+    match value {
+        Value::Record { val, .. } => {
+            for (name, value) in val.to_mut().iter_mut() {
+                if key.is_match(name) {
+                    *value = Value::string(REDACTED, value.span());
+                } else {
+                    redact_keys(value, key);
+                }
+            }
+        }
+        Value::List { vals, .. } => vals.iter_mut().for_each(|value| redact_keys(value, key)),
+        _ => (),
+    }
+}
+
+#[test]
+fn test_redact_method_args_and_result() {
+    use nu_protocol::record;
+
+    let redaction = Redaction {
+        rules: vec![
+            RedactionRule {
+                interface: "org.freedesktop.Secret.*".into(),
+                member: "Unlock*".into(),
+                arg: Some(1),
+                key: None,
+            },
+            RedactionRule {
+                interface: "org.freedesktop.NetworkManager.**".into(),
+                member: "*".into(),
+                arg: None,
+                key: Some("psk".into()),
+            },
+        ],
+    };
+
+    let mut args = vec![Value::test_string("/item/1"), Value::test_string("hunter2")];
+    redaction.redact_args("org.freedesktop.Secret.Service", "UnlockItem", &mut args);
+    assert_eq!(args[0], Value::test_string("/item/1"));
+    assert_eq!(args[1], Value::test_string(REDACTED));
+
+    let settings = || {
+        Value::test_record(record!(
+            "802-11-wireless-security" => Value::test_record(record!(
+                "key-mgmt" => Value::test_string("wpa-psk"),
+                "psk" => Value::test_string("hunter2"),
+            )),
+        ))
+    };
+    let mut result = Value::test_list(vec![settings()]);
+    redaction.redact_result(
+        "org.freedesktop.NetworkManager.Settings.Connection",
+        "GetSettings",
+        &[],
+        &mut result,
+    );
+    let security = &result.as_list().unwrap()[0]
+        .get_data_by_key("802-11-wireless-security")
+        .unwrap();
+    assert_eq!(
+        security.get_data_by_key("key-mgmt"),
+        Some(Value::test_string("wpa-psk"))
+    );
+    assert_eq!(
+        security.get_data_by_key("psk"),
+        Some(Value::test_string(REDACTED))
+    );
+
+    // Rules with an argument index don't apply to results
+    let mut result = Value::test_string("unlocked");
+    redaction.redact_result("org.freedesktop.Secret.Service", "Unlock", &[], &mut result);
+    assert_eq!(result, Value::test_string("unlocked"));
+}
+
+#[test]
+fn test_redact_properties() {
+    use nu_protocol::record;
+
+    let redaction = Redaction {
+        rules: vec![RedactionRule {
+            interface: "com.example.Vault".into(),
+            member: "Token".into(),
+            arg: None,
+            key: None,
+        }],
+    };
+    let string = Value::test_string;
+
+    let mut args = vec![string("com.example.Vault"), string("Token"), string("abc")];
+    redaction.redact_args(PROPERTIES, "Set", &mut args);
+    assert_eq!(args[2], string(REDACTED));
+
+    let mut args = vec![string("com.example.Vault"), string("Name"), string("abc")];
+    redaction.redact_args(PROPERTIES, "Set", &mut args);
+    assert_eq!(args[2], string("abc"));
+
+    let mut result = string("abc");
+    let args = [string("com.example.Vault"), string("Token")];
+    redaction.redact_result(PROPERTIES, "Get", &args, &mut result);
+    assert_eq!(result, string(REDACTED));
+
+    let mut result = Value::test_list(vec![Value::test_record(record!(
+        "Name" => string("vault"),
+        "Token" => string("abc"),
+    ))]);
+    redaction.redact_result(PROPERTIES, "GetAll", &args[..1], &mut result);
+    let properties = &result.as_list().unwrap()[0];
+    assert_eq!(properties.get_data_by_key("Name"), Some(string("vault")));
+    assert_eq!(properties.get_data_by_key("Token"), Some(string(REDACTED)));
+
+    let err = redaction.redact_arg_error(
+        PROPERTIES,
+        "Set",
+        &[string("com.example.Vault"), string("Token"), string("abc")],
+        2,
+        LabeledError::new("Failed to convert").with_label("invalid: abc", Default::default()),
+    );
+    assert_eq!(err.labels[0].text, REDACTED);
+}
@@ -0,0 +1,259 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    os::unix::{
+        fs::DirBuilderExt,
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use dbus::channel::Channel;
+
+use crate::{
+    address::DbusAddress,
+    auth::{self, AuthMechanism},
+};
+
+/// A listening unix socket in a fresh temporary directory that only our own user can access.
+/// Both are removed when dropped.
+pub struct PrivateSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    dir: PathBuf,
+}
+
+impl PrivateSocket {
+    pub fn bind() -> io::Result<PrivateSocket> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "nu_plugin_dbus-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Only our own user can reach the socket, so we don't have to check credentials
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let path = dir.join("bus");
+        match UnixListener::bind(&path) {
+            Ok(listener) => Ok(PrivateSocket {
+                listener,
+                path,
+                dir,
+            }),
+            Err(err) => {
+                let _ = std::fs::remove_dir(&dir);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PrivateSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_dir(&self.dir);
+    }
+}
+
+/// The two directions of a connection to a server, and what to do to close the connection once
+/// we're done writing
+pub struct Transport {
+    pub reader: Box<dyn Read + Send>,
+    pub writer: Box<dyn Write + Send>,
+    pub close: Box<dyn FnOnce() + Send>,
+}
+
+/// Connect to the server at the address, authenticating with the first of the mechanisms that the
+/// server accepts
+///
+/// libdbus doesn't let clients choose how they authenticate, so we do the handshake ourselves.
+/// libdbus then connects to a private socket instead, and once it has authenticated to us, we
+/// relay everything between it and the server. File descriptors can't be passed through the relay.
+pub fn open_with_auth(
+    address: &DbusAddress,
+    mechanisms: &[AuthMechanism],
+) -> Result<Channel, String> {
+    let Transport {
+        mut reader,
+        mut writer,
+        close,
+    } = connect(address).map_err(|err| format!("can't connect: {err}"))?;
+    let (_, guid) = auth::client_handshake(&mut reader, &mut writer, mechanisms, None)
+        .map_err(|err| err.to_string())?;
+
+    let socket = PrivateSocket::bind().map_err(|err| format!("can't start the relay: {err}"))?;
+    let listener = socket
+        .listener()
+        .try_clone()
+        .map_err(|err| format!("can't start the relay: {err}"))?;
+    std::thread::Builder::new()
+        .name("dbus auth relay".into())
+        .spawn(move || -> io::Result<()> {
+            let (mut local, _) = listener.accept()?;
+            // We can't forward file descriptors, because we relay messages as bytes
+            let rest = auth::server_handshake(&mut local, &guid, false)?;
+            writer.write_all(&rest)?;
+            relay(local, reader, writer, close);
+            Ok(())
+        })
+        .map_err(|err| format!("can't start the relay: {err}"))?;
+
+    let result = Channel::open_private(&DbusAddress::unix_path(socket.path()).to_string());
+    if result.is_err() {
+        // Wake up the relay thread so that it gives up
+        let _ = UnixStream::connect(socket.path());
+    }
+    // The socket isn't needed anymore once libdbus is connected to it
+    drop(socket);
+    result.map_err(|err| err.to_string())
+}
+
+/// Copy data in both directions until either side closes
+fn relay(
+    local: UnixStream,
+    mut reader: Box<dyn Read + Send>,
+    mut writer: Box<dyn Write + Send>,
+    close: Box<dyn FnOnce() + Send>,
+) {
+    let upstream = local.try_clone().map(|mut local_reader| {
+        std::thread::spawn(move || {
+            let _ = io::copy(&mut local_reader, &mut writer);
+            drop(writer);
+            close();
+        })
+    });
+    let _ = io::copy(&mut reader, &mut &local);
+    let _ = local.shutdown(Shutdown::Both);
+    if let Ok(upstream) = upstream {
+        let _ = upstream.join();
+    }
+}
+
+/// Open a connection to the server without doing anything else, as libdbus would
+pub fn connect(address: &DbusAddress) -> io::Result<Transport> {
+    match address.transport.as_str() {
+        "unix" => {
+            let stream = match (address.get("path"), address.get("abstract")) {
+                (Some(path), _) => UnixStream::connect(path)?,
+                (None, Some(name)) => connect_abstract(name)?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "only unix addresses with path or abstract can be connected to",
+                    ))
+                }
+            };
+            let (reader, closer) = (stream.try_clone()?, stream.try_clone()?);
+            Ok(Transport {
+                reader: Box::new(reader),
+                writer: Box::new(stream),
+                close: Box::new(move || {
+                    let _ = closer.shutdown(Shutdown::Write);
+                }),
+            })
+        }
+        "tcp" | "nonce-tcp" => {
+            let host = address.get("host").unwrap_or("localhost");
+            let port = address
+                .get("port")
+                .and_then(|port| port.parse::<u16>().ok())
+                .filter(|port| *port != 0)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "a port must be specified")
+                })?;
+            let family = address.get("family");
+
+            let mut last_err = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for {host}"),
+            );
+            let mut stream = None;
+            for socket_addr in (host, port).to_socket_addrs()? {
+                match family {
+                    Some("ipv4") if !socket_addr.is_ipv4() => continue,
+                    Some("ipv6") if !socket_addr.is_ipv6() => continue,
+                    _ => (),
+                }
+                match TcpStream::connect(socket_addr) {
+                    Ok(connected) => {
+                        stream = Some(connected);
+                        break;
+                    }
+                    Err(err) => last_err = err,
+                }
+            }
+            let mut stream = stream.ok_or(last_err)?;
+
+            if let Some(noncefile) = address.get("noncefile") {
+                stream.write_all(&std::fs::read(noncefile)?)?;
+            }
+
+            let (reader, closer) = (stream.try_clone()?, stream.try_clone()?);
+            Ok(Transport {
+                reader: Box::new(reader),
+                writer: Box::new(stream),
+                close: Box::new(move || {
+                    let _ = closer.shutdown(Shutdown::Write);
+                }),
+            })
+        }
+        "unixexec" => {
+            let path = address.get("path").unwrap_or_default();
+            let mut command = Command::new(path);
+            if let Some(argv0) = address.get("argv0") {
+                command.arg0(argv0);
+            }
+            // Like libdbus, take arguments until one is missing
+            command.args(
+                (1..)
+                    .map_while(|n| address.get(&format!("argv{n}")))
+                    .collect::<Vec<_>>(),
+            );
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+            Ok(Transport {
+                reader: Box::new(stdout),
+                writer: Box::new(stdin),
+                // stdin is closed by then, which should make the program exit
+                close: Box::new(move || {
+                    let _ = child.wait();
+                }),
+            })
+        }
+        other => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{other} addresses aren't supported with --auth"),
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> io::Result<UnixStream> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    UnixStream::connect_addr(&SocketAddr::from_abstract_name(name.as_bytes())?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_name: &str) -> io::Result<UnixStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
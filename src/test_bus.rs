@@ -0,0 +1,1083 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::CString,
+    io::{self, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use dbus::{strings::ErrorName, Message, MessageType};
+
+use crate::{
+    auth::{random_guid, server_handshake},
+    relay::PrivateSocket,
+};
+
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+
+// Flags for RequestName
+const ALLOW_REPLACEMENT: u32 = 0x1;
+const REPLACE_EXISTING: u32 = 0x2;
+const DO_NOT_QUEUE: u32 = 0x4;
+
+// Replies for RequestName
+const PRIMARY_OWNER: u32 = 1;
+const IN_QUEUE: u32 = 2;
+const EXISTS: u32 = 3;
+const ALREADY_OWNER: u32 = 4;
+
+// Replies for ReleaseName
+const RELEASED: u32 = 1;
+const NON_EXISTENT: u32 = 2;
+const NOT_OWNER: u32 = 3;
+
+// Reply for StartServiceByName, as there is nothing to start
+const START_REPLY_ALREADY_RUNNING: u32 = 2;
+
+/// A minimal D-Bus message broker running inside this process, listening on a temporary unix
+/// socket
+///
+/// It supports enough of the `org.freedesktop.DBus` interface for clients to connect, own names,
+/// find each other and subscribe to signals, which makes it suitable for hermetic tests. The bus
+/// shuts down and removes its socket when dropped.
+pub struct TestBus {
+    address: String,
+    socket: PrivateSocket,
+    shared: Arc<Shared>,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl TestBus {
+    /// Start a new bus on a socket in a fresh temporary directory
+    pub fn start() -> io::Result<TestBus> {
+        let socket = PrivateSocket::bind()?;
+        let guid = random_guid();
+        let address = format!(
+            "unix:path={},guid={}",
+            crate::address::escape(socket.path().as_os_str().as_bytes()),
+            guid
+        );
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(BusState {
+                guid,
+                ..BusState::default()
+            }),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let accept_thread = {
+            let shared = shared.clone();
+            let listener = socket.listener().try_clone()?;
+            std::thread::Builder::new()
+                .name("dbus test-bus".into())
+                .spawn(move || accept_loop(shared, listener))?
+        };
+
+        Ok(TestBus {
+            address,
+            socket,
+            shared,
+            accept_thread: Some(accept_thread),
+        })
+    }
+
+    /// The address clients can use to connect to the bus
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The path of the bus's socket
+    #[cfg(test)]
+    pub fn socket_path(&self) -> &std::path::Path {
+        self.socket.path()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+
+        // Wake up the accept loop so it notices the shutdown
+        let _ = UnixStream::connect(self.socket.path());
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+
+        // Disconnect everyone. Dropping the senders also stops the writer threads
+        let mut state = self.shared.lock();
+        for conn in state.conns.values() {
+            let _ = conn.stream.shutdown(std::net::Shutdown::Both);
+        }
+        state.conns.clear();
+        state.names.clear();
+        // The socket and its directory are removed when the fields are dropped
+    }
+}
+
+struct Shared {
+    state: Mutex<BusState>,
+    shutdown: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BusState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn accept_loop(shared: Arc<Shared>, listener: UnixListener) {
+    let mut next_id = 0;
+    for stream in listener.incoming() {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let Ok(shutdown_handle) = stream.try_clone() else {
+            continue;
+        };
+
+        next_id += 1;
+        let id = next_id;
+        shared.lock().conns.insert(
+            id,
+            Conn {
+                stream: shutdown_handle,
+                tx: None,
+                unique_name: None,
+                matches: vec![],
+            },
+        );
+
+        let shared = shared.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("dbus test-bus conn {id}"))
+            .spawn(move || {
+                let _ = serve_conn(&shared, id, stream);
+                shared.lock().disconnect(id);
+            });
+        if spawned.is_err() {
+            break;
+        }
+    }
+}
+
+/// Authenticate a client and then handle its messages until it disconnects
+fn serve_conn(shared: &Shared, id: u64, mut stream: UnixStream) -> io::Result<()> {
+    // Don't hold the lock during the handshake, which waits on the client
+    let guid = shared.lock().guid.clone();
+    let mut buf = server_handshake(&mut stream, &guid, true)?;
+
+    // Messages to the client are queued and written by a separate thread, so that a client that
+    // isn't reading can't block the whole bus
+    let (tx, rx) = mpsc::channel::<Outgoing>();
+    let mut writer = stream.try_clone()?;
+    std::thread::Builder::new()
+        .name(format!("dbus test-bus writer {id}"))
+        .spawn(move || {
+            for (bytes, fds) in rx {
+                if send_with_fds(&mut writer, &bytes, &fds).is_err() {
+                    break;
+                }
+            }
+        })?;
+    match shared.lock().conns.get_mut(&id) {
+        Some(conn) => conn.tx = Some(tx),
+        None => return Ok(()),
+    }
+
+    let mut chunk = [0; 4096];
+    // File descriptors received, in order, until the messages they came with are handled
+    let mut fds = VecDeque::new();
+    loop {
+        // Handle every complete message in the buffer
+        while buf.len() >= 16 {
+            let needed = Message::demarshal_bytes_needed(&buf[..16])
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad message header"))?;
+            if buf.len() < needed {
+                break;
+            }
+            let mut bytes = buf.drain(..needed).collect::<Vec<_>>();
+            let fd_count = replace_unix_fds(&mut bytes, 0);
+            if fd_count > fds.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file descriptors missing",
+                ));
+            }
+            let message_fds = fds.drain(..fd_count).collect::<Vec<_>>();
+            let message = Message::demarshal(&bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+            if !shared.lock().handle_message(id, message, &message_fds) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "protocol violation",
+                ));
+            }
+        }
+
+        let len = recv_with_fds(&stream, &mut chunk, &mut fds)?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// Read from a socket, keeping any file descriptors that come with the bytes
+fn recv_with_fds(
+    stream: &UnixStream,
+    buf: &mut [u8],
+    fds: &mut VecDeque<OwnedFd>,
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // u64 to align it for the headers
+    let mut control = [0u64; 64];
+    // SAFETY: msghdr is plain data, and every pointer in it is set to a live buffer below
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let len = loop {
+        // SAFETY: the buffers are valid for the lengths given
+        let len = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        match len {
+            0.. => break len as usize,
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ => return Err(io::Error::last_os_error()),
+        }
+    };
+
+    // SAFETY: the control messages were written by the kernel within the buffer, and the file
+    // descriptors in them are now ours
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg);
+                let count = ((*cmsg).cmsg_len as usize - data.offset_from(cmsg.cast()) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for index in 0..count {
+                    let fd = data.cast::<RawFd>().add(index).read_unaligned();
+                    fds.push_back(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(len)
+}
+
+/// Write bytes to a socket, passing file descriptors along with them
+fn send_with_fds(stream: &mut UnixStream, bytes: &[u8], fds: &[OwnedFd]) -> io::Result<()> {
+    if fds.is_empty() {
+        return stream.write_all(bytes);
+    }
+
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut _,
+        iov_len: bytes.len(),
+    };
+    let fds_len = std::mem::size_of_val(fds) as u32;
+    // SAFETY: only computes a size
+    let control_len = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // u64 to align it for the header
+    let mut control = vec![0u64; control_len.div_ceil(8)];
+    // SAFETY: msghdr is plain data, and every pointer in it is set to a live buffer below
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control_len as _;
+    // SAFETY: the control buffer has room for a header and all of the file descriptors
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+        for (index, fd) in fds.iter().enumerate() {
+            data.add(index).write_unaligned(fd.as_raw_fd());
+        }
+    }
+
+    let sent = loop {
+        // SAFETY: the buffers are valid for the lengths given
+        let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        match sent {
+            0.. => break sent as usize,
+            _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ => return Err(io::Error::last_os_error()),
+        }
+    };
+    // The file descriptors went with the first byte, so the rest can be written as usual
+    stream.write_all(&bytes[sent..])
+}
+
+#[derive(Default)]
+struct BusState {
+    guid: String,
+    next_unique: u64,
+    next_serial: u32,
+    conns: HashMap<u64, Conn>,
+    names: BTreeMap<String, NameEntry>,
+}
+
+struct Conn {
+    /// Only used to force a shutdown
+    stream: UnixStream,
+    /// Queue for messages to write to the client (set after authentication)
+    tx: Option<mpsc::Sender<Outgoing>>,
+    /// Assigned on Hello
+    unique_name: Option<String>,
+    matches: Vec<MatchRule>,
+}
+
+struct NameEntry {
+    owner: u64,
+    owner_flags: u32,
+    queue: VecDeque<(u64, u32)>,
+}
+
+/// A message to write to a client, and the file descriptors that come with it
+type Outgoing = (Vec<u8>, Vec<OwnedFd>);
+
+/// The result of a method call handled by the bus itself
+type BusReply = Result<Message, (&'static str, String)>;
+
+impl BusState {
+    fn unique_name(&self, id: u64) -> String {
+        self.conns
+            .get(&id)
+            .and_then(|conn| conn.unique_name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Find the connection that currently owns a (unique or well-known) name
+    fn resolve(&self, name: &str) -> Option<u64> {
+        if name.starts_with(':') {
+            self.conns
+                .iter()
+                .find(|(_, conn)| conn.unique_name.as_deref() == Some(name))
+                .map(|(id, _)| *id)
+        } else {
+            self.names.get(name).map(|entry| entry.owner)
+        }
+    }
+
+    /// Handle a message from a client and the file descriptors that came with it. Returns
+    /// `false` if the client should be disconnected.
+    fn handle_message(&mut self, id: u64, mut message: Message, fds: &[OwnedFd]) -> bool {
+        let Some(unique_name) = self.conns.get(&id).and_then(|c| c.unique_name.clone()) else {
+            // The first message must be Hello
+            if message.destination().as_deref() == Some(BUS_NAME)
+                && message.member().as_deref() == Some("Hello")
+            {
+                self.hello(id, &message);
+                return true;
+            } else {
+                return false;
+            }
+        };
+
+        // Don't let clients pretend to be someone else
+        message.set_sender(Some(unique_name.into()));
+
+        match message.destination().map(|d| d.to_string()) {
+            Some(dest) if dest == BUS_NAME => {
+                if message.msg_type() == MessageType::MethodCall {
+                    let reply = self.bus_method(id, &message);
+                    if !message.get_no_reply() {
+                        let reply = reply.unwrap_or_else(|(name, text)| {
+                            message.error(
+                                &ErrorName::new(name).unwrap(),
+                                &CString::new(text.replace('\0', "")).unwrap(),
+                            )
+                        });
+                        self.emit(id, reply);
+                    }
+                }
+            }
+            Some(dest) => match self.resolve(&dest) {
+                Some(target) => self.deliver(target, &message, fds),
+                None => {
+                    if message.msg_type() == MessageType::MethodCall && !message.get_no_reply() {
+                        // Nothing can be activated, so this is the error for trying to
+                        let (name, text) = if message.get_auto_start() {
+                            service_unknown(&dest)
+                        } else {
+                            (
+                                "org.freedesktop.DBus.Error.NameHasNoOwner",
+                                format!("Name \"{dest}\" does not exist"),
+                            )
+                        };
+                        let error = message
+                            .error(&ErrorName::new(name).unwrap(), &CString::new(text).unwrap());
+                        self.emit(id, error);
+                    }
+                }
+            },
+            None => {
+                if message.msg_type() == MessageType::Signal {
+                    self.broadcast(&message, fds);
+                }
+            }
+        }
+        true
+    }
+
+    fn hello(&mut self, id: u64, message: &Message) {
+        self.next_unique += 1;
+        let unique_name = format!(":1.{}", self.next_unique);
+        if let Some(conn) = self.conns.get_mut(&id) {
+            conn.unique_name = Some(unique_name.clone());
+        }
+
+        let mut reply = message.method_return().append1(&unique_name);
+        reply.set_destination(Some(unique_name.clone().into()));
+        self.emit(id, reply);
+        self.name_acquired(id, &unique_name);
+        self.name_owner_changed(&unique_name, "", &unique_name);
+    }
+
+    /// Handle a method call addressed to the bus itself
+    fn bus_method(&mut self, id: u64, message: &Message) -> BusReply {
+        let invalid_args = |err: dbus::arg::TypeMismatchError| {
+            ("org.freedesktop.DBus.Error.InvalidArgs", err.to_string())
+        };
+
+        let interface = message.interface().map(|i| i.to_string());
+        let member = message.member().map(|m| m.to_string()).unwrap_or_default();
+
+        match (interface.as_deref(), &member[..]) {
+            (Some("org.freedesktop.DBus.Peer"), "Ping") => Ok(message.method_return()),
+            (Some("org.freedesktop.DBus.Peer"), _) => Err(unknown_method(&member)),
+            (Some(BUS_NAME) | None, "Hello") => Err((
+                "org.freedesktop.DBus.Error.Failed",
+                "Already handled an Hello message".into(),
+            )),
+            (Some(BUS_NAME) | None, "RequestName") => {
+                let (name, flags): (String, u32) = message.read2().map_err(invalid_args)?;
+                let result = self.request_name(id, &name, flags)?;
+                Ok(message.method_return().append1(result))
+            }
+            (Some(BUS_NAME) | None, "ReleaseName") => {
+                let name: String = message.read1().map_err(invalid_args)?;
+                let result = self.release_name(id, &name)?;
+                Ok(message.method_return().append1(result))
+            }
+            (Some(BUS_NAME) | None, "ListNames") => {
+                let names = std::iter::once(BUS_NAME.to_owned())
+                    .chain(self.conns.values().filter_map(|c| c.unique_name.clone()))
+                    .chain(self.names.keys().cloned())
+                    .collect::<Vec<_>>();
+                Ok(message.method_return().append1(names))
+            }
+            (Some(BUS_NAME) | None, "ListActivatableNames") => {
+                Ok(message.method_return().append1(vec![BUS_NAME]))
+            }
+            (Some(BUS_NAME) | None, "StartServiceByName") => {
+                let (name, _flags): (String, u32) = message.read2().map_err(invalid_args)?;
+                if name == BUS_NAME || self.resolve(&name).is_some() {
+                    Ok(message.method_return().append1(START_REPLY_ALREADY_RUNNING))
+                } else {
+                    Err(service_unknown(&name))
+                }
+            }
+            (Some(BUS_NAME) | None, "NameHasOwner") => {
+                let name: String = message.read1().map_err(invalid_args)?;
+                let has_owner = name == BUS_NAME || self.resolve(&name).is_some();
+                Ok(message.method_return().append1(has_owner))
+            }
+            (Some(BUS_NAME) | None, "GetNameOwner") => {
+                let name: String = message.read1().map_err(invalid_args)?;
+                let owner = if name == BUS_NAME {
+                    BUS_NAME.to_owned()
+                } else {
+                    let owner = self.resolve(&name).ok_or_else(|| {
+                        (
+                            "org.freedesktop.DBus.Error.NameHasNoOwner",
+                            format!("Could not get owner of name '{name}': no such name"),
+                        )
+                    })?;
+                    self.unique_name(owner)
+                };
+                Ok(message.method_return().append1(owner))
+            }
+            (Some(BUS_NAME) | None, "AddMatch") => {
+                let rule: String = message.read1().map_err(invalid_args)?;
+                let rule = MatchRule::parse(&rule)
+                    .map_err(|err| ("org.freedesktop.DBus.Error.MatchRuleInvalid", err))?;
+                if let Some(conn) = self.conns.get_mut(&id) {
+                    conn.matches.push(rule);
+                }
+                Ok(message.method_return())
+            }
+            (Some(BUS_NAME) | None, "RemoveMatch") => {
+                let rule: String = message.read1().map_err(invalid_args)?;
+                let rule = MatchRule::parse(&rule)
+                    .map_err(|err| ("org.freedesktop.DBus.Error.MatchRuleInvalid", err))?;
+                let matches = &mut self.conns.get_mut(&id).unwrap().matches;
+                let index = matches.iter().position(|r| *r == rule).ok_or((
+                    "org.freedesktop.DBus.Error.MatchRuleNotFound",
+                    "The given match rule wasn't found and can't be removed".into(),
+                ))?;
+                matches.remove(index);
+                Ok(message.method_return())
+            }
+            (Some(BUS_NAME) | None, "GetId") => Ok(message.method_return().append1(&self.guid)),
+            _ => Err(unknown_method(&member)),
+        }
+    }
+
+    fn request_name(
+        &mut self,
+        id: u64,
+        name: &str,
+        flags: u32,
+    ) -> Result<u32, (&'static str, String)> {
+        if name.starts_with(':') || name == BUS_NAME || dbus::strings::BusName::new(name).is_err() {
+            return Err((
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                format!("Cannot acquire the name '{name}'"),
+            ));
+        }
+
+        let Some(entry) = self.names.get_mut(name) else {
+            self.names.insert(
+                name.to_owned(),
+                NameEntry {
+                    owner: id,
+                    owner_flags: flags,
+                    queue: VecDeque::new(),
+                },
+            );
+            self.name_acquired(id, name);
+            let unique_name = self.unique_name(id);
+            self.name_owner_changed(name, "", &unique_name);
+            return Ok(PRIMARY_OWNER);
+        };
+
+        if entry.owner == id {
+            entry.owner_flags = flags;
+            return Ok(ALREADY_OWNER);
+        }
+
+        entry.queue.retain(|(queued, _)| *queued != id);
+
+        if flags & REPLACE_EXISTING != 0 && entry.owner_flags & ALLOW_REPLACEMENT != 0 {
+            let old_owner = entry.owner;
+            if entry.owner_flags & DO_NOT_QUEUE == 0 {
+                entry.queue.push_front((old_owner, entry.owner_flags));
+            }
+            entry.owner = id;
+            entry.owner_flags = flags;
+            self.name_lost(old_owner, name);
+            self.name_acquired(id, name);
+            let (old, new) = (self.unique_name(old_owner), self.unique_name(id));
+            self.name_owner_changed(name, &old, &new);
+            Ok(PRIMARY_OWNER)
+        } else if flags & DO_NOT_QUEUE != 0 {
+            Ok(EXISTS)
+        } else {
+            entry.queue.push_back((id, flags));
+            Ok(IN_QUEUE)
+        }
+    }
+
+    fn release_name(&mut self, id: u64, name: &str) -> Result<u32, (&'static str, String)> {
+        if name.starts_with(':') || name == BUS_NAME {
+            return Err((
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                format!("Cannot release the name '{name}'"),
+            ));
+        }
+
+        let Some(entry) = self.names.get_mut(name) else {
+            return Ok(NON_EXISTENT);
+        };
+
+        if entry.owner == id {
+            self.name_lost(id, name);
+            self.remove_owner(name);
+            Ok(RELEASED)
+        } else if entry.queue.iter().any(|(queued, _)| *queued == id) {
+            entry.queue.retain(|(queued, _)| *queued != id);
+            Ok(RELEASED)
+        } else {
+            Ok(NOT_OWNER)
+        }
+    }
+
+    /// Pass ownership of a name to the next in the queue, or remove it if nobody is waiting
+    fn remove_owner(&mut self, name: &str) {
+        let Some(entry) = self.names.get_mut(name) else {
+            return;
+        };
+        let old_owner = self
+            .conns
+            .get(&entry.owner)
+            .and_then(|c| c.unique_name.clone());
+        match entry.queue.pop_front() {
+            Some((new_owner, flags)) => {
+                entry.owner = new_owner;
+                entry.owner_flags = flags;
+                self.name_acquired(new_owner, name);
+                let new = self.unique_name(new_owner);
+                self.name_owner_changed(name, &old_owner.unwrap_or_default(), &new);
+            }
+            None => {
+                self.names.remove(name);
+                self.name_owner_changed(name, &old_owner.unwrap_or_default(), "");
+            }
+        }
+    }
+
+    /// Clean up after a client that went away
+    fn disconnect(&mut self, id: u64) {
+        let Some(conn) = self.conns.get(&id) else {
+            return;
+        };
+        let _ = conn.stream.shutdown(std::net::Shutdown::Both);
+
+        let owned = self
+            .names
+            .iter()
+            .filter(|(_, entry)| entry.owner == id)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in owned {
+            self.remove_owner(&name);
+        }
+        for entry in self.names.values_mut() {
+            entry.queue.retain(|(queued, _)| *queued != id);
+        }
+
+        if let Some(unique_name) = self.conns.remove(&id).and_then(|c| c.unique_name) {
+            self.name_owner_changed(&unique_name, &unique_name, "");
+        }
+    }
+
+    fn name_acquired(&mut self, id: u64, name: &str) {
+        let destination = self.unique_name(id);
+        self.bus_signal(Some(&destination), "NameAcquired", |m| m.append1(name));
+    }
+
+    fn name_lost(&mut self, id: u64, name: &str) {
+        let destination = self.unique_name(id);
+        self.bus_signal(Some(&destination), "NameLost", |m| m.append1(name));
+    }
+
+    fn name_owner_changed(&mut self, name: &str, old: &str, new: &str) {
+        self.bus_signal(None, "NameOwnerChanged", |m| m.append3(name, old, new));
+    }
+
+    /// Send a signal from the bus, either to one destination or to everyone interested
+    fn bus_signal(
+        &mut self,
+        destination: Option<&str>,
+        member: &str,
+        append: impl FnOnce(Message) -> Message,
+    ) {
+        let mut signal = append(Message::new_signal(BUS_PATH, BUS_NAME, member).unwrap());
+        self.stamp(&mut signal);
+        match destination {
+            Some(destination) => {
+                signal.set_destination(Some(destination.to_owned().into()));
+                if let Some(id) = self.resolve(destination) {
+                    self.deliver(id, &signal, &[]);
+                }
+            }
+            None => self.broadcast(&signal, &[]),
+        }
+    }
+
+    /// Give a message from the bus its serial and sender
+    fn stamp(&mut self, message: &mut Message) {
+        self.next_serial = self.next_serial.wrapping_add(1).max(1);
+        message.set_serial(self.next_serial);
+        message.set_sender(Some(BUS_NAME.into()));
+    }
+
+    /// Send a message from the bus to a connection
+    fn emit(&mut self, id: u64, mut message: Message) {
+        self.stamp(&mut message);
+        self.deliver(id, &message, &[]);
+    }
+
+    /// Send a message to every connection that has a matching rule
+    fn broadcast(&self, message: &Message, fds: &[OwnedFd]) {
+        for (id, conn) in &self.conns {
+            if conn.unique_name.is_some() && conn.matches.iter().any(|r| r.matches(self, message)) {
+                self.deliver(*id, message, fds);
+            }
+        }
+    }
+
+    fn deliver(&self, id: u64, message: &Message, fds: &[OwnedFd]) {
+        if let Some(tx) = self.conns.get(&id).and_then(|c| c.tx.as_ref()) {
+            let mut bytes = vec![];
+            let _ = message.marshal(|chunk| {
+                bytes.extend_from_slice(chunk);
+                Ok::<(), ()>(())
+            });
+            replace_unix_fds(&mut bytes, fds.len());
+            let Ok(fds) = fds.iter().map(OwnedFd::try_clone).collect() else {
+                return;
+            };
+            let _ = tx.send((bytes, fds));
+        }
+    }
+}
+
+fn service_unknown(name: &str) -> (&'static str, String) {
+    (
+        "org.freedesktop.DBus.Error.ServiceUnknown",
+        format!("The name {name} was not provided by any .service files"),
+    )
+}
+
+fn unknown_method(member: &str) -> (&'static str, String) {
+    (
+        "org.freedesktop.DBus.Error.UnknownMethod",
+        format!("Unknown method {member:?} on the bus"),
+    )
+}
+
+/// The header field that says how many file descriptors come with a message
+const UNIX_FDS_FIELD: u8 = 9;
+
+/// Find the value of the UNIX_FDS header field in a marshalled message
+fn unix_fds_offset(bytes: &[u8]) -> Option<usize> {
+    let read_u32 = |at: usize| {
+        let word = bytes.get(at..at + 4)?.try_into().ok()?;
+        Some(match bytes[0] {
+            b'B' => u32::from_be_bytes(word),
+            _ => u32::from_le_bytes(word),
+        } as usize)
+    };
+
+    // Each field is a byte code and a variant, aligned to 8 bytes
+    let end = 16 + read_u32(12)?;
+    let mut pos = 16;
+    while pos < end {
+        let code = *bytes.get(pos)?;
+        let signature = bytes.get(pos + 2..pos + 2 + *bytes.get(pos + 1)? as usize)?;
+        let value = pos + 3 + signature.len();
+        let value_end = match signature {
+            b"u" => value.next_multiple_of(4) + 4,
+            b"s" | b"o" => value.next_multiple_of(4) + 4 + read_u32(value.next_multiple_of(4))? + 1,
+            b"g" => value + 1 + *bytes.get(value)? as usize + 1,
+            _ => return None,
+        };
+        if code == UNIX_FDS_FIELD {
+            return (signature == b"u").then(|| value.next_multiple_of(4));
+        }
+        pos = value_end.next_multiple_of(8);
+    }
+    None
+}
+
+/// Set the number of file descriptors that come with a marshalled message, returning the number
+/// it had before
+///
+/// libdbus won't demarshal a message without the file descriptors it's supposed to have, so the
+/// bus sets it to zero while it handles the message, and back again when delivering it.
+fn replace_unix_fds(bytes: &mut [u8], count: usize) -> usize {
+    let Some(offset) = unix_fds_offset(bytes) else {
+        return 0;
+    };
+    let big_endian = bytes[0] == b'B';
+    let word = &mut bytes[offset..offset + 4];
+    let old = match big_endian {
+        true => u32::from_be_bytes(word.try_into().unwrap()),
+        false => u32::from_le_bytes(word.try_into().unwrap()),
+    };
+    word.copy_from_slice(&match big_endian {
+        true => (count as u32).to_be_bytes(),
+        false => (count as u32).to_le_bytes(),
+    });
+    old as usize
+}
+
+/// A subset of the D-Bus match rule language, as used by AddMatch
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MatchRule {
+    msg_type: Option<MessageType>,
+    sender: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    path: Option<String>,
+    path_namespace: Option<String>,
+    destination: Option<String>,
+    args: Vec<(usize, String)>,
+}
+
+impl MatchRule {
+    fn parse(input: &str) -> Result<MatchRule, String> {
+        let mut rule = MatchRule::default();
+        let mut chars = input.chars().peekable();
+
+        loop {
+            // Key, up to =
+            let key: String = chars.by_ref().take_while(|&c| c != '=').collect();
+            let key = key.trim();
+            if key.is_empty() {
+                break;
+            }
+
+            // Value, with shell-like quoting, up to an unquoted comma
+            let mut value = String::new();
+            let mut quoted = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\'' => quoted = !quoted,
+                    '\\' if !quoted && chars.peek() == Some(&'\'') => {
+                        value.push(chars.next().unwrap());
+                    }
+                    ',' if !quoted => break,
+                    c => value.push(c),
+                }
+            }
+            if quoted {
+                return Err(format!("unterminated quote in value of {key:?}"));
+            }
+
+            match key {
+                "type" => {
+                    rule.msg_type = Some(match &value[..] {
+                        "method_call" => MessageType::MethodCall,
+                        "method_return" => MessageType::MethodReturn,
+                        "error" => MessageType::Error,
+                        "signal" => MessageType::Signal,
+                        other => return Err(format!("unknown message type {other:?}")),
+                    })
+                }
+                "sender" => rule.sender = Some(value),
+                "interface" => rule.interface = Some(value),
+                "member" => rule.member = Some(value),
+                "path" => rule.path = Some(value),
+                "path_namespace" => rule.path_namespace = Some(value),
+                "destination" => rule.destination = Some(value),
+                "eavesdrop" => (),
+                _ => match key.strip_prefix("arg").map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n < 64 => rule.args.push((n, value)),
+                    _ => return Err(format!("unsupported key {key:?} in match rule")),
+                },
+            }
+        }
+        Ok(rule)
+    }
+
+    fn matches(&self, bus: &BusState, message: &Message) -> bool {
+        fn field_matches(expected: &Option<String>, actual: Option<String>) -> bool {
+            expected.is_none() || *expected == actual
+        }
+
+        if self.msg_type.is_some_and(|t| t != message.msg_type()) {
+            return false;
+        }
+
+        if let Some(sender) = &self.sender {
+            let actual = message.sender().map(|s| s.to_string());
+            // The rule can refer to a well-known name, but messages always have a unique sender
+            let resolved = bus.resolve(sender).map(|id| bus.unique_name(id));
+            if actual.as_ref() != Some(sender) && actual != resolved {
+                return false;
+            }
+        }
+
+        if !field_matches(&self.interface, message.interface().map(|s| s.to_string()))
+            || !field_matches(&self.member, message.member().map(|s| s.to_string()))
+            || !field_matches(&self.path, message.path().map(|s| s.to_string()))
+            || !field_matches(
+                &self.destination,
+                message.destination().map(|s| s.to_string()),
+            )
+        {
+            return false;
+        }
+
+        if let Some(namespace) = &self.path_namespace {
+            let path = message.path().map(|s| s.to_string()).unwrap_or_default();
+            let in_namespace = namespace == "/"
+                || path == *namespace
+                || path
+                    .strip_prefix(&namespace[..])
+                    .is_some_and(|rest| rest.starts_with('/'));
+            if !in_namespace {
+                return false;
+            }
+        }
+
+        self.args.iter().all(|(index, expected)| {
+            let mut iter = message.iter_init();
+            for _ in 0..*index {
+                iter.next();
+            }
+            iter.get::<&str>() == Some(&expected[..])
+        })
+    }
+}
+
+#[cfg(test)]
+fn test_connect(bus: &TestBus) -> dbus::channel::Channel {
+    let mut channel = dbus::channel::Channel::open_private(bus.address()).unwrap();
+    channel.register().unwrap();
+    channel
+}
+
+#[cfg(test)]
+fn test_bus_call(
+    channel: &dbus::channel::Channel,
+    member: &str,
+    append: impl FnOnce(Message) -> Message,
+) -> Result<Message, dbus::Error> {
+    let message = append(Message::new_method_call(BUS_NAME, BUS_PATH, BUS_NAME, member).unwrap());
+    channel.send_with_reply_and_block(message, std::time::Duration::from_secs(5))
+}
+
+#[test]
+fn test_bus_hello_and_names() {
+    let bus = TestBus::start().unwrap();
+    let client = test_connect(&bus);
+    let unique_name = client.unique_name().unwrap().to_owned();
+    assert!(unique_name.starts_with(":1."));
+
+    let reply = test_bus_call(&client, "RequestName", |m| {
+        m.append2("com.example.Test", 0u32)
+    });
+    assert_eq!(reply.unwrap().read1::<u32>().unwrap(), PRIMARY_OWNER);
+
+    let names: Vec<String> = test_bus_call(&client, "ListNames", |m| m)
+        .unwrap()
+        .read1()
+        .unwrap();
+    assert!(names.contains(&BUS_NAME.to_owned()));
+    assert!(names.contains(&unique_name));
+    assert!(names.contains(&"com.example.Test".to_owned()));
+
+    let owner: String = test_bus_call(&client, "GetNameOwner", |m| m.append1("com.example.Test"))
+        .unwrap()
+        .read1()
+        .unwrap();
+    assert_eq!(owner, unique_name);
+
+    let reply = test_bus_call(&client, "ReleaseName", |m| m.append1("com.example.Test"));
+    assert_eq!(reply.unwrap().read1::<u32>().unwrap(), RELEASED);
+    assert!(test_bus_call(&client, "GetNameOwner", |m| m.append1("com.example.Test")).is_err());
+}
+
+#[test]
+fn test_bus_routes_method_calls() {
+    let bus = TestBus::start().unwrap();
+    let caller = test_connect(&bus);
+    let service = test_connect(&bus);
+    test_bus_call(&service, "RequestName", |m| {
+        m.append2("com.example.Echo", 0u32)
+    })
+    .unwrap();
+
+    let service_thread = std::thread::spawn(move || loop {
+        let message = service
+            .blocking_pop_message(std::time::Duration::from_secs(5))
+            .unwrap()
+            .expect("timed out waiting for call");
+        if message.member().as_deref() == Some("Echo") {
+            let arg: String = message.read1().unwrap();
+            service.send(message.method_return().append1(arg)).unwrap();
+            service.flush();
+            break;
+        }
+    });
+
+    let call = Message::new_method_call("com.example.Echo", "/", "com.example.Echo", "Echo")
+        .unwrap()
+        .append1("hello");
+    let reply = caller
+        .send_with_reply_and_block(call, std::time::Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(reply.read1::<&str>().unwrap(), "hello");
+    service_thread.join().unwrap();
+
+    let call =
+        Message::new_method_call("com.example.Missing", "/", "com.example.Echo", "Echo").unwrap();
+    let err = caller
+        .send_with_reply_and_block(call, std::time::Duration::from_secs(5))
+        .unwrap_err();
+    assert_eq!(
+        err.name(),
+        Some("org.freedesktop.DBus.Error.ServiceUnknown")
+    );
+}
+
+#[test]
+fn test_bus_name_owner_changed_match() {
+    let bus = TestBus::start().unwrap();
+    let watcher = test_connect(&bus);
+    test_bus_call(&watcher, "AddMatch", |m| {
+        m.append1("type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged',arg0='com.example.Watched'")
+    })
+    .unwrap();
+
+    let owner = test_connect(&bus);
+    test_bus_call(&owner, "RequestName", |m| {
+        m.append2("com.example.Watched", 0u32)
+    })
+    .unwrap();
+
+    // Skip the NameAcquired signal for our own unique name
+    let signal = std::iter::from_fn(|| {
+        watcher
+            .blocking_pop_message(std::time::Duration::from_secs(5))
+            .unwrap()
+    })
+    .find(|m| m.member().as_deref() == Some("NameOwnerChanged"))
+    .expect("timed out waiting for signal");
+    let (name, old, new): (String, String, String) = signal.read3().unwrap();
+    assert_eq!(name, "com.example.Watched");
+    assert_eq!(old, "");
+    assert_eq!(new, owner.unique_name().unwrap());
+}
+
+#[test]
+fn test_bus_shuts_down_on_drop() {
+    let bus = TestBus::start().unwrap();
+    let address = bus.address().to_owned();
+    let socket_path = bus.socket.path().to_owned();
+    drop(bus);
+    assert!(!socket_path.exists());
+    assert!(!socket_path.parent().unwrap().exists());
+    assert!(dbus::channel::Channel::open_private(&address).is_err());
+}
+
+#[test]
+fn test_bus_unaffected_by_stalled_handshake() {
+    let bus = TestBus::start().unwrap();
+    // Connect, but never authenticate
+    let _stalled = UnixStream::connect(bus.socket.path()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let client = test_connect(&bus);
+    assert!(client.unique_name().is_some());
+    drop(bus);
+}